@@ -0,0 +1,91 @@
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::http::{HeaderMap, StatusCode};
+use actix_web::web::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use cache::{CacheStorage, CachedResponse, ResponseCache};
+
+const THREADS: usize = 8;
+const OPS_PER_THREAD: usize = 1_000;
+const KEYS: usize = 1_000;
+// cache holds half of the keys, so writes of the other half hit a full cache
+const CAPACITY: usize = KEYS / 2;
+// every n-th operation is a write
+const WRITE_RATIO: usize = 4;
+
+fn concurrent_read_write(c: &mut Criterion) {
+    let keys = (0..KEYS)
+        .map(|i| Arc::from(format!("/bench/{}", i)))
+        .collect::<Arc<[Arc<str>]>>();
+
+    let mut group = c.benchmark_group("concurrent_read_write");
+    group.throughput(Throughput::Elements((THREADS * OPS_PER_THREAD) as u64));
+    for shards in [1, 4, 16] {
+        let cache = Arc::new(ResponseCache::with_shards(CAPACITY, shards));
+        for key in keys.iter() {
+            cache.put(key.clone(), response(), expire_at());
+        }
+        assert_eq!(CAPACITY, cache.stats().entries);
+
+        group.bench_with_input(BenchmarkId::from_parameter(shards), &shards, |b, _| {
+            b.iter_custom(|iters| run_threads(&cache, &keys, iters))
+        });
+    }
+    group.finish();
+}
+
+// threads are spawned before timing starts and run all iterations
+fn run_threads(cache: &Arc<ResponseCache>, keys: &Arc<[Arc<str>]>, iters: u64) -> Duration {
+    let start = Arc::new(Barrier::new(THREADS + 1));
+    let done = Arc::new(Barrier::new(THREADS + 1));
+    let handles = (0..THREADS)
+        .map(|t| {
+            let cache = cache.clone();
+            let keys = keys.clone();
+            let start = start.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                start.wait();
+                for _ in 0..iters {
+                    for i in 0..OPS_PER_THREAD {
+                        let key = &keys[(t * OPS_PER_THREAD + i * 7) % keys.len()];
+                        if i % WRITE_RATIO == 0 {
+                            cache.put(key.clone(), response(), expire_at());
+                        } else {
+                            cache.get(key);
+                        }
+                    }
+                }
+                done.wait();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    start.wait();
+    let started = Instant::now();
+    done.wait();
+    let elapsed = started.elapsed();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    elapsed
+}
+
+fn response() -> CachedResponse {
+    CachedResponse {
+        status_code: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body: Bytes::from_static(b"cached body").into(),
+        ttl: expire_at(),
+    }
+}
+
+fn expire_at() -> SystemTime {
+    SystemTime::now() + Duration::from_secs(60)
+}
+
+criterion_group!(benches, concurrent_read_write);
+criterion_main!(benches);
@@ -0,0 +1,561 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::expiring_cache::{CacheEntryInfo, CachedBody, CachedResponse, FileBody};
+use crate::storage::CacheStats;
+
+const ENTRY_EXT: &str = "cache";
+const TMP_EXT: &str = "tmp";
+const META_LEN_SIZE: u64 = 8;
+
+/// Serialized entry metadata, shared with cache snapshots.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct EntryMeta {
+    key: String,
+    status: u16,
+    headers: Vec<(String, Vec<u8>)>,
+    ttl: u64,
+    expire_at: u64,
+}
+
+struct DiskEntry {
+    id: u64,
+    status_code: StatusCode,
+    headers: HeaderMap,
+    ttl: SystemTime,
+    expire_at: SystemTime,
+    body_offset: u64,
+    body_len: u64,
+    size: u64,
+    last_access: u64,
+    hits: u64,
+}
+
+struct DiskIndex {
+    entries: HashMap<Arc<str>, DiskEntry>,
+    // eviction order of keys, by last access tick and by expiry
+    by_access: BTreeMap<u64, Arc<str>>,
+    by_expiry: BTreeMap<(SystemTime, u64), Arc<str>>,
+    size: u64,
+    body_size: u64,
+    tick: u64,
+    evictions: u64,
+}
+
+/// Second cache tier, each entry is stored in its own file as
+/// meta length, serialized meta (key, status, headers, ttl) and body.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_size: u64,
+    next_id: AtomicU64,
+    index: Mutex<DiskIndex>,
+}
+
+impl DiskEntry {
+    fn info(&self, key: &Arc<str>) -> CacheEntryInfo {
+        CacheEntryInfo {
+            key: key.clone(),
+            status_code: self.status_code,
+            size: self.body_len as usize,
+            ttl: self
+                .ttl
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+            hits: self.hits,
+        }
+    }
+}
+
+impl EntryMeta {
+    pub(crate) fn new(k: &str, v: &CachedResponse, expire_at: SystemTime) -> Self {
+        EntryMeta {
+            key: k.to_string(),
+            status: v.status_code.as_u16(),
+            headers: v
+                .headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            ttl: to_unix_millis(v.ttl),
+            expire_at: to_unix_millis(expire_at),
+        }
+    }
+
+    pub(crate) fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub(crate) fn status_code(&self) -> io::Result<StatusCode> {
+        StatusCode::from_u16(self.status).map_err(|e| io::Error::other(e.to_string()))
+    }
+
+    pub(crate) fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_bytes(value),
+            ) {
+                headers.append(name, value);
+            }
+        }
+        headers
+    }
+
+    pub(crate) fn ttl(&self) -> SystemTime {
+        from_unix_millis(self.ttl)
+    }
+
+    pub(crate) fn expire_at(&self) -> SystemTime {
+        from_unix_millis(self.expire_at)
+    }
+}
+
+impl DiskIndex {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn insert(&mut self, k: Arc<str>, mut entry: DiskEntry) {
+        entry.last_access = self.next_tick();
+        self.size += entry.size;
+        self.body_size += entry.body_len;
+        self.by_access.insert(entry.last_access, k.clone());
+        self.by_expiry
+            .insert((entry.expire_at, entry.id), k.clone());
+        self.entries.insert(k, entry);
+    }
+
+    fn remove(&mut self, k: &str) -> Option<DiskEntry> {
+        let entry = self.entries.remove(k)?;
+        self.size -= entry.size;
+        self.body_size -= entry.body_len;
+        self.by_access.remove(&entry.last_access);
+        self.by_expiry.remove(&(entry.expire_at, entry.id));
+        Some(entry)
+    }
+
+    // marks entry as the most recently used one
+    fn touch(&mut self, k: &str) -> Option<&DiskEntry> {
+        let tick = self.next_tick();
+        let entry = self.entries.get_mut(k)?;
+        if let Some(key) = self.by_access.remove(&entry.last_access) {
+            self.by_access.insert(tick, key);
+        }
+        entry.last_access = tick;
+        entry.hits += 1;
+        Some(entry)
+    }
+
+    // expired entries go first, then least recently used ones
+    fn evict_candidate(&self) -> Option<Arc<str>> {
+        let now = SystemTime::now();
+        self.by_expiry
+            .iter()
+            .next()
+            .filter(|((expire_at, _), _)| *expire_at <= now)
+            .map(|(_, k)| k)
+            .or_else(|| self.by_access.values().next())
+            .cloned()
+    }
+}
+
+impl DiskCache {
+    /// Opens cache directory and rebuilds the index from stored entries.
+    pub fn open<P>(dir: P, max_size: u64) -> io::Result<Self>
+    where
+        P: Into<PathBuf>,
+    {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let disk_cache = DiskCache {
+            dir,
+            max_size,
+            next_id: AtomicU64::new(0),
+            index: Mutex::new(DiskIndex {
+                entries: HashMap::new(),
+                by_access: BTreeMap::new(),
+                by_expiry: BTreeMap::new(),
+                size: 0,
+                body_size: 0,
+                tick: 0,
+                evictions: 0,
+            }),
+        };
+        disk_cache.rebuild_index()?;
+        Ok(disk_cache)
+    }
+
+    /// Entry file is opened once the index lock is released, so lookups aren't serialized on disk reads.
+    pub fn get(&self, k: &str) -> Option<CachedResponse> {
+        let (id, status_code, headers, ttl, offset, len) = {
+            let mut index = self.index_lock();
+            match index.entries.get(k) {
+                Some(entry) if entry.expire_at > SystemTime::now() => {}
+                Some(_) => {
+                    self.remove_entry(&mut index, k);
+                    return None;
+                }
+                None => return None,
+            }
+            let entry = index.touch(k)?;
+            (
+                entry.id,
+                entry.status_code,
+                entry.headers.clone(),
+                entry.ttl,
+                entry.body_offset,
+                entry.body_len,
+            )
+        };
+        // opened file stays readable when entry is evicted while body is streamed
+        let path = self.entry_path(id, ENTRY_EXT);
+        match File::open(&path) {
+            Ok(file) => Some(CachedResponse {
+                status_code,
+                headers,
+                body: CachedBody::File(FileBody {
+                    file: Arc::new(file),
+                    offset,
+                    len,
+                }),
+                ttl,
+            }),
+            Err(e) => {
+                warn!("Error opening disk cache entry {:?}. Err = {}", &path, e);
+                let mut index = self.index_lock();
+                // entry could have been replaced meanwhile
+                if index.entries.get(k).is_some_and(|entry| entry.id == id) {
+                    self.remove_entry(&mut index, k);
+                }
+                None
+            }
+        }
+    }
+
+    /// Returns false when entry doesn't fit in size budget.
+    pub fn put(&self, k: Arc<str>, v: &CachedResponse, expire_at: SystemTime) -> io::Result<bool> {
+        let body = v.body.load()?;
+        let meta = EntryMeta::new(&k, v, expire_at);
+        let meta = bincode::serialize(&meta).map_err(|e| io::Error::other(e.to_string()))?;
+        let body_offset = META_LEN_SIZE + meta.len() as u64;
+        let size = body_offset + body.len() as u64;
+        if size > self.max_size {
+            debug!("Entry {} exceeds disk cache size budget", &k);
+            return Ok(false);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = self.entry_path(id, TMP_EXT);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&(meta.len() as u64).to_le_bytes())?;
+        file.write_all(&meta)?;
+        file.write_all(&body)?;
+        fs::rename(&tmp_path, self.entry_path(id, ENTRY_EXT))?;
+
+        let mut index = self.index_lock();
+        self.remove_entry(&mut index, &k);
+        index.insert(
+            k,
+            DiskEntry {
+                id,
+                status_code: v.status_code,
+                headers: v.headers.clone(),
+                ttl: v.ttl,
+                expire_at,
+                body_offset,
+                body_len: body.len() as u64,
+                size,
+                last_access: 0,
+                hits: 0,
+            },
+        );
+        self.evict_over_budget(&mut index);
+        Ok(true)
+    }
+
+    pub fn remove(&self, k: &str) -> bool {
+        self.remove_entry(&mut self.index_lock(), k)
+    }
+
+    pub fn purge(&self, predicate: &dyn Fn(&str) -> bool) -> usize {
+        self.purge_where(|k, _| predicate(k))
+    }
+
+    pub fn remove_expired(&self) -> usize {
+        let now = SystemTime::now();
+        self.purge_where(|_, entry| entry.expire_at <= now)
+    }
+
+    pub fn entry(&self, k: &str) -> Option<CacheEntryInfo> {
+        let now = SystemTime::now();
+        self.index_lock()
+            .entries
+            .get_key_value(k)
+            .filter(|(_, entry)| entry.expire_at > now)
+            .map(|(key, entry)| entry.info(key))
+    }
+
+    pub fn entries(&self) -> Vec<CacheEntryInfo> {
+        let now = SystemTime::now();
+        self.index_lock()
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expire_at > now)
+            .map(|(key, entry)| entry.info(key))
+            .collect()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let index = self.index_lock();
+        CacheStats {
+            entries: index.entries.len(),
+            size: index.body_size as usize,
+            evictions: index.evictions,
+            ..CacheStats::default()
+        }
+    }
+
+    fn rebuild_index(&self) -> io::Result<()> {
+        let mut index = self.index_lock();
+        let mut max_id = 0;
+        for dir_entry in fs::read_dir(&self.dir)? {
+            let path = dir_entry?.path();
+            let id = match Self::entry_id(&path) {
+                Some((id, ext)) if ext == ENTRY_EXT => id,
+                // leftover of an interrupted write
+                Some(_) => {
+                    Self::remove_file(&path);
+                    continue;
+                }
+                None => continue,
+            };
+            max_id = max_id.max(id + 1);
+
+            match Self::read_entry(&path, id) {
+                Ok((key, entry)) if entry.expire_at > SystemTime::now() => {
+                    // same key written twice, keep the latest one
+                    if index.entries.get(&key).is_some_and(|e| e.id > id) {
+                        Self::remove_file(&path);
+                        continue;
+                    }
+                    self.remove_entry(&mut index, &key);
+                    index.insert(key, entry);
+                }
+                Ok(_) => Self::remove_file(&path),
+                Err(e) => {
+                    warn!(
+                        "Removing unreadable disk cache entry {:?}. Err = {}",
+                        &path, e
+                    );
+                    Self::remove_file(&path);
+                }
+            }
+        }
+        self.next_id.store(max_id, Ordering::Relaxed);
+        self.evict_over_budget(&mut index);
+        debug!(
+            "Disk cache index rebuilt with {} entries",
+            index.entries.len()
+        );
+        Ok(())
+    }
+
+    fn read_entry(path: &Path, id: u64) -> io::Result<(Arc<str>, DiskEntry)> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut meta_len = [0; META_LEN_SIZE as usize];
+        file.read_exact(&mut meta_len)?;
+        let meta_len = u64::from_le_bytes(meta_len);
+        let mut meta = vec![0; meta_len as usize];
+        file.read_exact(&mut meta)?;
+        let meta: EntryMeta =
+            bincode::deserialize(&meta).map_err(|e| io::Error::other(e.to_string()))?;
+        let body_offset = META_LEN_SIZE + meta_len;
+
+        let entry = DiskEntry {
+            id,
+            status_code: meta.status_code()?,
+            headers: meta.headers(),
+            ttl: meta.ttl(),
+            expire_at: meta.expire_at(),
+            body_offset,
+            body_len: size.saturating_sub(body_offset),
+            size,
+            last_access: 0,
+            hits: 0,
+        };
+        Ok((Arc::from(meta.key()), entry))
+    }
+
+    fn purge_where<F>(&self, predicate: F) -> usize
+    where
+        F: Fn(&str, &DiskEntry) -> bool,
+    {
+        let mut index = self.index_lock();
+        let keys = index
+            .entries
+            .iter()
+            .filter(|(k, entry)| predicate(k, entry))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<Arc<str>>>();
+        keys.iter()
+            .filter(|k| self.remove_entry(&mut index, k))
+            .count()
+    }
+
+    fn evict_over_budget(&self, index: &mut DiskIndex) {
+        while index.size > self.max_size {
+            match index.evict_candidate() {
+                Some(k) => {
+                    self.remove_entry(index, &k);
+                    index.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove_entry(&self, index: &mut DiskIndex, k: &str) -> bool {
+        if let Some(entry) = index.remove(k) {
+            Self::remove_file(&self.entry_path(entry.id, ENTRY_EXT));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn entry_path(&self, id: u64, ext: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.{}", id, ext))
+    }
+
+    fn entry_id(path: &Path) -> Option<(u64, &str)> {
+        let id = u64::from_str_radix(path.file_stem()?.to_str()?, 16).ok()?;
+        Some((id, path.extension()?.to_str()?))
+    }
+
+    fn remove_file(path: &Path) {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Error removing disk cache file {:?}. Err = {}", path, e);
+        }
+    }
+
+    fn index_lock(&self) -> MutexGuard<'_, DiskIndex> {
+        self.index.lock().expect("Disk cache index lock poisoned!")
+    }
+}
+
+fn to_unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+fn from_unix_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::http::{HeaderMap, HeaderValue, StatusCode};
+    use actix_web::web::Bytes;
+
+    use crate::disk_cache::DiskCache;
+    use crate::CachedResponse;
+
+    #[test]
+    fn should_rebuild_index_on_open() {
+        let dir = test_dir("rebuild");
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        let key: Arc<str> = Arc::from("/a");
+        {
+            let disk = DiskCache::open(&dir, 1024).unwrap();
+            disk.put(key.clone(), &resp("body"), expire_at).unwrap();
+        }
+
+        let disk = DiskCache::open(&dir, 1024).unwrap();
+        let cached = disk.get(&key).unwrap();
+        assert_eq!(Bytes::from("body"), cached.body.load().unwrap());
+        assert_eq!("text/plain", cached.headers.get(CONTENT_TYPE).unwrap());
+        assert!(!cached.expired());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_evict_least_recently_used_over_budget() {
+        let dir = test_dir("evict");
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        let body = "x".repeat(400);
+        let disk = DiskCache::open(&dir, 1024).unwrap();
+        disk.put(Arc::from("1"), &resp(&body), expire_at).unwrap();
+        disk.put(Arc::from("2"), &resp(&body), expire_at).unwrap();
+        assert!(disk.get("1").is_some());
+        disk.put(Arc::from("3"), &resp(&body), expire_at).unwrap();
+
+        assert!(disk.get("1").is_some());
+        assert!(disk.get("2").is_none());
+        assert!(disk.get("3").is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_evict_expired_before_least_recently_used() {
+        let dir = test_dir("evict-expired");
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        let body = "x".repeat(400);
+        let disk = DiskCache::open(&dir, 1024).unwrap();
+        disk.put(Arc::from("1"), &resp(&body), expire_at).unwrap();
+        let expired_at = SystemTime::now() - Duration::from_secs(1);
+        disk.put(Arc::from("2"), &resp(&body), expired_at).unwrap();
+        disk.put(Arc::from("3"), &resp(&body), expire_at).unwrap();
+
+        assert_eq!(2, disk.stats().entries);
+        assert!(disk.get("1").is_some());
+        assert!(disk.get("3").is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_read_body_of_entry_removed_after_lookup() {
+        let dir = test_dir("removed");
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        let disk = DiskCache::open(&dir, 1024).unwrap();
+        disk.put(Arc::from("1"), &resp("body"), expire_at).unwrap();
+
+        let cached = disk.get("1").unwrap();
+        assert!(disk.remove("1"));
+        assert_eq!(Bytes::from("body"), cached.body.load().unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn resp(body: &str) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        CachedResponse {
+            status_code: StatusCode::OK,
+            headers,
+            body: Bytes::from(body.to_string()).into(),
+            ttl: SystemTime::now() + Duration::from_secs(60),
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("roxy-disk-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+}
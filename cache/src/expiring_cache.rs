@@ -1,75 +1,626 @@
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::hash::BuildHasher;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use actix_web::http::{HeaderMap, StatusCode};
 use actix_web::web::Bytes;
-use blocking_delay_queue::{BlockingDelayQueue, DelayItem};
-use crossbeam::sync::{ShardedLock, ShardedLockWriteGuard};
+use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
+use log::error;
 
-const INSERT_TIMEOUT: Duration = Duration::from_millis(1);
+use crate::disk_cache::DiskCache;
+use crate::storage::{CacheStats, CacheStorage};
+
+const MAX_SHARDS: usize = 16;
+const MIN_SHARD_CAPACITY: usize = 64;
+// full shard scans for expired entries at most this often, sweeper removes them anyway
+const RECLAIM_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct CachedResponse {
     pub status_code: StatusCode,
     pub headers: HeaderMap,
-    pub body: Bytes,
-    pub ttl: Instant,
+    pub body: CachedBody,
+    pub ttl: SystemTime,
 }
 
-pub struct ResponseCache {
-    cache: ShardedLock<HashMap<Arc<str>, CachedResponse>>,
-    expire_q: BlockingDelayQueue<DelayItem<Arc<str>>>,
+#[derive(Clone)]
+pub enum CachedBody {
+    Bytes(Bytes),
+    File(FileBody),
+}
+
+/// Body stored in disk cache file at given offset, file is opened on lookup
+/// so it stays readable when the entry is evicted meanwhile.
+/// Only the disk tier creates it, other storages keep bodies in `CachedBody::Bytes`.
+#[derive(Clone, Debug)]
+pub struct FileBody {
+    pub(crate) file: Arc<File>,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+pub struct CacheEntryInfo {
+    pub key: Arc<str>,
+    pub status_code: StatusCode,
+    pub size: usize,
+    pub ttl: Duration,
+    pub hits: u64,
+}
+
+struct CacheEntry {
+    response: CachedResponse,
+    expire_at: SystemTime,
+    hits: AtomicU64,
+    last_access: AtomicU64,
+    // tick of the entry's item in eviction queue
+    queued_at: u64,
+}
+
+// keys are queued in insertion order, items of removed or replaced entries are skipped
+struct Entries {
+    map: HashMap<Arc<str>, CacheEntry>,
+    queue: VecDeque<(Arc<str>, u64)>,
+    reclaimed_at: Option<Instant>,
+}
+
+// each shard has its own lock and lru order
+struct Shard {
+    entries: ShardedLock<Entries>,
+    tick: AtomicU64,
+}
+
+// in-memory capacity is shared by all shards so keys hashed unevenly can still use all of it
+struct Slots {
     capacity: usize,
+    used: AtomicUsize,
+}
+
+// spilled entry is returned when shard is full, entry is handed back when shard has nothing to spill
+enum PutResult {
+    Stored(Option<(Arc<str>, CacheEntry)>),
+    Full(CachedResponse),
+    Rejected,
+}
+
+// cumulative since cache creation
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expirations: AtomicU64,
+    rejected_inserts: AtomicU64,
+    bytes_stored: AtomicU64,
+    // body bytes of entries held in memory
+    size: AtomicUsize,
+}
+
+/// In-memory cache partitioned into hash shards so writers of different keys don't contend.
+pub struct ResponseCache {
+    shards: Vec<Shard>,
+    hasher: RandomState,
+    slots: Slots,
+    disk: Option<DiskCache>,
+    counters: Counters,
 }
 
 impl CachedResponse {
     pub fn expired(&self) -> bool {
-        self.ttl < Instant::now()
+        self.ttl < SystemTime::now()
+    }
+}
+
+impl CachedBody {
+    pub fn len(&self) -> usize {
+        match self {
+            CachedBody::Bytes(bytes) => bytes.len(),
+            CachedBody::File(file) => file.len as usize,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Part of the body, range has to be within body length.
+    pub fn slice(&self, start: u64, len: u64) -> CachedBody {
+        match self {
+            CachedBody::Bytes(bytes) => {
+                CachedBody::Bytes(bytes.slice(start as usize..(start + len) as usize))
+            }
+            CachedBody::File(file) => CachedBody::File(FileBody {
+                file: file.file.clone(),
+                offset: file.offset + start,
+                len,
+            }),
+        }
+    }
+
+    /// Reads whole body in memory.
+    pub fn load(&self) -> io::Result<Bytes> {
+        match self {
+            CachedBody::Bytes(bytes) => Ok(bytes.clone()),
+            CachedBody::File(file) => file.read(0, file.len),
+        }
+    }
+}
+
+impl FileBody {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads `len` bytes starting `start` bytes into the body.
+    pub fn read(&self, start: u64, len: u64) -> io::Result<Bytes> {
+        let mut buf = vec![0; len as usize];
+        self.file.read_exact_at(&mut buf, self.offset + start)?;
+        Ok(Bytes::from(buf))
+    }
+}
+
+impl From<Bytes> for CachedBody {
+    fn from(bytes: Bytes) -> Self {
+        CachedBody::Bytes(bytes)
+    }
+}
+
+impl CacheEntry {
+    fn expired(&self, now: SystemTime) -> bool {
+        self.expire_at <= now
+    }
+
+    fn info(&self, key: &Arc<str>) -> CacheEntryInfo {
+        CacheEntryInfo {
+            key: key.clone(),
+            status_code: self.response.status_code,
+            size: self.response.body.len(),
+            ttl: self
+                .response
+                .ttl
+                .duration_since(SystemTime::now())
+                .unwrap_or_default(),
+            hits: self.hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Entries {
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn reclaim_due(&mut self) -> bool {
+        let now = Instant::now();
+        let due = self
+            .reclaimed_at
+            .is_none_or(|at| now.duration_since(at) >= RECLAIM_INTERVAL);
+        if due {
+            self.reclaimed_at = Some(now);
+        }
+        due
+    }
+
+    fn insert(&mut self, k: Arc<str>, entry: CacheEntry) -> Option<CacheEntry> {
+        self.queue.push_back((k.clone(), entry.queued_at));
+        self.map.insert(k, entry)
+    }
+
+    // drops queue items left behind by removed and replaced entries
+    fn compact(&mut self) {
+        let map = &self.map;
+        self.queue
+            .retain(|(k, queued_at)| map.get(k).is_some_and(|e| e.queued_at == *queued_at));
+    }
+}
+
+impl Counters {
+    // drops size of entry removed from memory
+    fn release(&self, entry: &CacheEntry) {
+        self.size
+            .fetch_sub(entry.response.body.len(), Ordering::Relaxed);
+    }
+}
+
+impl Slots {
+    fn try_take(&self) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                (used < self.capacity).then_some(used + 1)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, count: usize) {
+        self.used.fetch_sub(count, Ordering::AcqRel);
+    }
+}
+
+impl Shard {
+    fn new() -> Self {
+        Shard {
+            entries: ShardedLock::new(Entries {
+                map: HashMap::new(),
+                queue: VecDeque::new(),
+                reclaimed_at: None,
+            }),
+            tick: AtomicU64::new(0),
+        }
+    }
+
+    fn put(
+        &self,
+        k: Arc<str>,
+        v: CachedResponse,
+        ttl: SystemTime,
+        spill: bool,
+        slots: &Slots,
+        counters: &Counters,
+    ) -> PutResult {
+        let mut entries = self.write_lock();
+        // replacing an entry doesn't take more space
+        let mut full = !entries.map.contains_key(&k) && !slots.try_take();
+        if full && entries.reclaim_due() {
+            // reclaim expired entries not yet removed by sweeper
+            let expired = Self::retain_live(&mut entries.map, SystemTime::now(), counters);
+            counters
+                .expirations
+                .fetch_add(expired as u64, Ordering::Relaxed);
+            slots.release(expired);
+            full = !slots.try_take();
+        }
+        // evicted entry's slot is taken over by the new one
+        let spilled = match (full, spill) {
+            (false, _) => None,
+            (true, false) => return PutResult::Rejected,
+            (true, true) => match Self::evict_lru(&mut entries, counters) {
+                None => return PutResult::Full(v),
+                Some(evicted) => Self::unexpired(evicted, counters),
+            },
+        };
+
+        let tick = self.next_tick();
+        counters.size.fetch_add(v.body.len(), Ordering::Relaxed);
+        let entry = CacheEntry {
+            response: v,
+            expire_at: ttl,
+            hits: AtomicU64::new(0),
+            last_access: AtomicU64::new(tick),
+            queued_at: tick,
+        };
+        if let Some(replaced) = entries.insert(k, entry) {
+            counters.release(&replaced);
+        }
+        if entries.queue.len() > 2 * entries.len() + MIN_SHARD_CAPACITY {
+            entries.compact();
+        }
+        PutResult::Stored(spilled)
+    }
+
+    fn get(&self, k: &str, now: SystemTime) -> Option<CachedResponse> {
+        self.read_lock()
+            .map
+            .get(k)
+            .filter(|entry| !entry.expired(now))
+            .map(|entry| {
+                entry.hits.fetch_add(1, Ordering::Relaxed);
+                entry.last_access.store(self.next_tick(), Ordering::Relaxed);
+                entry.response.clone()
+            })
+    }
+
+    fn retain<F>(&self, f: F, counters: &Counters) -> usize
+    where
+        F: Fn(&Arc<str>, &CacheEntry) -> bool,
+    {
+        Self::retain_entries(&mut self.write_lock().map, f, counters)
+    }
+
+    fn retain_live(
+        entries: &mut HashMap<Arc<str>, CacheEntry>,
+        now: SystemTime,
+        counters: &Counters,
+    ) -> usize {
+        Self::retain_entries(entries, |_, entry| !entry.expired(now), counters)
+    }
+
+    fn retain_entries<F>(
+        entries: &mut HashMap<Arc<str>, CacheEntry>,
+        f: F,
+        counters: &Counters,
+    ) -> usize
+    where
+        F: Fn(&Arc<str>, &CacheEntry) -> bool,
+    {
+        let len = entries.len();
+        entries.retain(|k, entry| {
+            let keep = f(k, entry);
+            if !keep {
+                counters.release(entry);
+            }
+            keep
+        });
+        len - entries.len()
+    }
+
+    // makes room for entry of another shard, live evicted entry is returned to be spilled
+    fn evict(&self, slots: &Slots, counters: &Counters) -> Option<(Arc<str>, CacheEntry)> {
+        let evicted = Self::evict_lru(&mut self.write_lock(), counters)?;
+        slots.release(1);
+        Self::unexpired(evicted, counters)
+    }
+
+    // expired entry isn't spilled, live one is counted as eviction only if it doesn't make it to disk
+    fn unexpired(
+        evicted: (Arc<str>, CacheEntry),
+        counters: &Counters,
+    ) -> Option<(Arc<str>, CacheEntry)> {
+        if evicted.1.expired(SystemTime::now()) {
+            counters.expirations.fetch_add(1, Ordering::Relaxed);
+            None
+        } else {
+            Some(evicted)
+        }
+    }
+
+    // oldest queued entry not accessed since it was queued is evicted,
+    // accessed ones are queued again (second chance)
+    fn evict_lru(entries: &mut Entries, counters: &Counters) -> Option<(Arc<str>, CacheEntry)> {
+        while let Some((k, queued_at)) = entries.queue.pop_front() {
+            let entry = match entries.map.get_mut(&k) {
+                Some(entry) if entry.queued_at == queued_at => entry,
+                _ => continue,
+            };
+            let last_access = *entry.last_access.get_mut();
+            if last_access > queued_at {
+                entry.queued_at = last_access;
+                entries.queue.push_back((k, last_access));
+                continue;
+            }
+            let evicted = entries.map.remove_entry(&k);
+            if let Some((_, entry)) = &evicted {
+                counters.release(entry);
+            }
+            return evicted;
+        }
+        None
+    }
+
+    fn next_tick(&self) -> u64 {
+        self.tick.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn read_lock(&self) -> ShardedLockReadGuard<'_, Entries> {
+        self.entries.read().expect("Cache map lock poisoned!")
+    }
+
+    fn write_lock(&self) -> ShardedLockWriteGuard<'_, Entries> {
+        self.entries.write().expect("Cache write lock poisoned!")
     }
 }
 
 impl ResponseCache {
+    /// Shard count grows with capacity, small caches are kept in a single shard.
     pub fn with_capacity(capacity: usize) -> Self {
-         ResponseCache {
-            cache: ShardedLock::new(HashMap::new()),
-            expire_q: BlockingDelayQueue::new_with_capacity(capacity),
-            capacity,
+        let shards = (capacity / MIN_SHARD_CAPACITY).clamp(1, MAX_SHARDS);
+        Self::with_shards(capacity, shards)
+    }
+
+    /// Capacity is shared by all shards, once it's reached entries are rejected or spilled
+    /// from the shard of the inserted key, or from the largest one when that shard is empty.
+    pub fn with_shards(capacity: usize, shards: usize) -> Self {
+        ResponseCache {
+            shards: (0..shards.max(1)).map(|_| Shard::new()).collect(),
+            hasher: RandomState::new(),
+            slots: Slots {
+                capacity,
+                used: AtomicUsize::new(0),
+            },
+            disk: None,
+            counters: Counters::default(),
         }
     }
 
-    pub fn expire_head(&self) {
-        let item = self.expire_q.take();
-        self.cache_write_lock().remove(&item.data);
+    /// Least recently used entries are spilled to disk once memory capacity is reached.
+    pub fn with_disk(mut self, disk: DiskCache) -> Self {
+        self.disk = Some(disk);
+        self
     }
 
-    pub fn put(&self, k: Arc<str>, v: CachedResponse, ttl: Instant) -> bool {
-        let mut cache = self.cache_write_lock();
-        if cache.len() < self.capacity {
-            // avoid blocking api, len should be same as map
-            let success = self
-                .expire_q
-                .offer(DelayItem::new(k.clone(), ttl), INSERT_TIMEOUT);
-            if success {
-                cache.insert(k, v);
+    /// Removes expired entries from memory and disk, expired entries are never served
+    /// so this only reclaims space. Returns number of removed entries.
+    pub fn remove_expired(&self) -> usize {
+        let now = SystemTime::now();
+        let removed = self.retain(|_, entry| !entry.expired(now))
+            + self.disk.as_ref().map_or(0, |disk| disk.remove_expired());
+        self.counters
+            .expirations
+            .fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    // live in-memory entries with their expiry, disk tier persists on its own
+    pub(crate) fn memory_entries(&self) -> Vec<(Arc<str>, CachedResponse, SystemTime)> {
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(
+                shard
+                    .read_lock()
+                    .map
+                    .iter()
+                    .filter(|(_, entry)| !entry.expired(now))
+                    .map(|(k, entry)| (k.clone(), entry.response.clone(), entry.expire_at)),
+            );
+        }
+        entries
+    }
+
+    fn retain<F>(&self, f: F) -> usize
+    where
+        F: Fn(&Arc<str>, &CacheEntry) -> bool,
+    {
+        let removed = self
+            .shards
+            .iter()
+            .map(|shard| shard.retain(&f, &self.counters))
+            .sum::<usize>();
+        self.slots.release(removed);
+        removed
+    }
+
+    fn largest_shard(&self) -> &Shard {
+        self.shards
+            .iter()
+            .max_by_key(|shard| shard.read_lock().len())
+            .expect("cache has at least one shard")
+    }
+
+    fn spill(&self, disk: &DiskCache, key: Arc<str>, entry: CacheEntry) {
+        let spilled = match disk.put(key.clone(), &entry.response, entry.expire_at) {
+            Ok(spilled) => spilled,
+            Err(e) => {
+                error!("Error spilling {} to disk cache. Err = {}", &key, e);
+                false
             }
-            success
+        };
+        if !spilled {
+            self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn shard(&self, k: &str) -> &Shard {
+        let idx = self.hasher.hash_one(k) as usize % self.shards.len();
+        &self.shards[idx]
+    }
+}
+
+impl CacheStorage for ResponseCache {
+    fn put(&self, k: Arc<str>, v: CachedResponse, ttl: SystemTime) -> bool {
+        let size = v.body.len() as u64;
+        let shard = self.shard(&k);
+        let spill = self.disk.is_some();
+        let mut result = shard.put(k.clone(), v, ttl, spill, &self.slots, &self.counters);
+        let mut evicted = None;
+        if let PutResult::Full(v) = result {
+            // room is made in another shard, put may still lose the freed slot to a concurrent one
+            evicted = self.largest_shard().evict(&self.slots, &self.counters);
+            result = shard.put(k.clone(), v, ttl, spill, &self.slots, &self.counters);
+        }
+        let (stored, spilled) = match result {
+            PutResult::Stored(spilled) => (true, spilled),
+            PutResult::Full(_) | PutResult::Rejected => (false, None),
+        };
+        if let Some(disk) = &self.disk {
+            if stored {
+                disk.remove(&k);
+            }
+            for (key, entry) in evicted.into_iter().chain(spilled) {
+                self.spill(disk, key, entry);
+            }
+        }
+        if stored {
+            self.counters
+                .bytes_stored
+                .fetch_add(size, Ordering::Relaxed);
+        } else {
+            self.counters
+                .rejected_inserts
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        stored
+    }
+
+    fn get(&self, k: &str) -> Option<CachedResponse> {
+        self.shard(k)
+            .get(k, SystemTime::now())
+            .or_else(|| self.disk.as_ref().and_then(|disk| disk.get(k)))
+    }
+
+    fn count_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.counters.hits
         } else {
-            false
+            &self.counters.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn remove(&self, k: &str) -> bool {
+        let removed = self.shard(k).write_lock().map.remove(k);
+        if let Some(entry) = &removed {
+            self.counters.release(entry);
+            self.slots.release(1);
         }
+        let removed_from_disk = self.disk.as_ref().is_some_and(|disk| disk.remove(k));
+        removed.is_some() || removed_from_disk
     }
 
-    pub fn get(&self, k: Arc<str>) -> Option<CachedResponse> {
-        self.cache
-            .read()
-            .expect("Cache map lock poisoned!")
-            .get(&k)
-            .map_or_else(|| None, |v| Some(v.clone()))
+    fn purge(&self, predicate: &dyn Fn(&str) -> bool) -> usize {
+        let purged = self.retain(|k, _| !predicate(k));
+        purged + self.disk.as_ref().map_or(0, |disk| disk.purge(predicate))
     }
 
-    fn cache_write_lock(&self) -> ShardedLockWriteGuard<'_, HashMap<Arc<str>, CachedResponse>> {
-        self.cache.write().expect("Cache write lock poisoned!")
+    fn entry(&self, k: &str) -> Option<CacheEntryInfo> {
+        let now = SystemTime::now();
+        let info = self
+            .shard(k)
+            .read_lock()
+            .map
+            .get_key_value(k)
+            .filter(|(_, entry)| !entry.expired(now))
+            .map(|(key, entry)| entry.info(key));
+        info.or_else(|| self.disk.as_ref().and_then(|disk| disk.entry(k)))
+    }
+
+    fn entries(&self) -> Vec<CacheEntryInfo> {
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(
+                shard
+                    .read_lock()
+                    .map
+                    .iter()
+                    .filter(|(_, entry)| !entry.expired(now))
+                    .map(|(key, entry)| entry.info(key)),
+            );
+        }
+        if let Some(disk) = &self.disk {
+            entries.extend(disk.entries());
+        }
+        entries
+    }
+
+    fn stats(&self) -> CacheStats {
+        let counters = &self.counters;
+        let mut stats = CacheStats {
+            hits: counters.hits.load(Ordering::Relaxed),
+            misses: counters.misses.load(Ordering::Relaxed),
+            evictions: counters.evictions.load(Ordering::Relaxed),
+            expirations: counters.expirations.load(Ordering::Relaxed),
+            rejected_inserts: counters.rejected_inserts.load(Ordering::Relaxed),
+            bytes_stored: counters.bytes_stored.load(Ordering::Relaxed),
+            entries: self.slots.used.load(Ordering::Relaxed),
+            size: counters.size.load(Ordering::Relaxed),
+        };
+        if let Some(disk) = &self.disk {
+            let disk_stats = disk.stats();
+            stats.entries += disk_stats.entries;
+            stats.size += disk_stats.size;
+            stats.evictions += disk_stats.evictions;
+        }
+        stats
     }
 }
 
@@ -78,16 +629,19 @@ mod tests {
     use actix_web::http::{HeaderMap, StatusCode};
     use std::sync::Arc;
     use std::thread;
-    use std::time::{Duration, Instant};
+    use std::time::{Duration, SystemTime};
 
     use actix_web::web::Bytes;
 
     use crate::expiring_cache::ResponseCache;
-    use crate::CachedResponse;
+    use crate::{CacheStorage, CachedBody, CachedResponse, DiskCache};
 
     impl ResponseCache {
         fn len(&self) -> usize {
-            self.cache.read().expect("Cache map lock poisoned!").len()
+            self.shards
+                .iter()
+                .map(|shard| shard.read_lock().len())
+                .sum()
         }
     }
 
@@ -96,33 +650,238 @@ mod tests {
         let ttl = Duration::from_millis(50);
         let cache = ResponseCache::with_capacity(1);
         let key: Arc<str> = Arc::from("1");
-        cache.put(key.clone(), dummy_resp(), Instant::now() + ttl);
-        assert!(cache.get(key.clone()).is_some());
+        cache.put(key.clone(), dummy_resp(), SystemTime::now() + ttl);
+        assert!(cache.get(&key).is_some());
         thread::sleep(ttl);
-        cache.expire_head();
-        assert!(cache.get(key.clone()).is_none());
+        assert!(cache.get(&key).is_none());
+        assert_eq!(1, cache.remove_expired());
+        assert_eq!(0, cache.len());
     }
 
     #[test]
     fn should_not_block_when_capacity_is_reached() {
-        let ttl = Instant::now() + Duration::from_millis(50);
+        let ttl = SystemTime::now() + Duration::from_millis(50);
         let cache = ResponseCache::with_capacity(1);
         let first_key: Arc<str> = Arc::from("1");
-        let second_key : Arc<str>= Arc::from("2");
+        let second_key: Arc<str> = Arc::from("2");
         let first = cache.put(first_key.clone(), dummy_resp(), ttl);
         let second = cache.put(second_key.clone(), dummy_resp(), ttl);
         assert_eq!(1, cache.len());
         assert!(first);
         assert!(!second);
-        assert!(cache.get(first_key.clone()).is_some());
+        assert!(cache.get(&first_key).is_some());
+    }
+
+    #[test]
+    fn should_count_cache_events() {
+        let cache = ResponseCache::with_capacity(1);
+        let key: Arc<str> = Arc::from("1");
+        let mut resp = dummy_resp();
+        resp.body = Bytes::from("body").into();
+        cache.put(
+            key.clone(),
+            resp,
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        cache.put(Arc::from("2"), dummy_resp(), SystemTime::now());
+        cache.count_lookup(cache.get(&key).is_some());
+        cache.count_lookup(cache.get("2").is_some());
+
+        let stats = cache.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(1, stats.misses);
+        assert_eq!(1, stats.rejected_inserts);
+        assert_eq!(4, stats.bytes_stored);
+        assert_eq!(4, stats.size);
+
+        cache.remove("1");
+        cache.put(Arc::from("2"), dummy_resp(), SystemTime::now());
+        cache.remove_expired();
+        let stats = cache.stats();
+        assert_eq!(1, stats.expirations);
+        assert_eq!(0, stats.entries);
+        assert_eq!(0, stats.size);
+    }
+
+    #[test]
+    fn should_purge_matching_values() {
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        let cache = ResponseCache::with_capacity(3);
+        cache.put(Arc::from("/a/1"), dummy_resp(), ttl);
+        cache.put(Arc::from("/a/2"), dummy_resp(), ttl);
+        cache.put(Arc::from("/b/1"), dummy_resp(), ttl);
+
+        assert!(cache.remove("/b/1"));
+        assert!(!cache.remove("/b/1"));
+        assert_eq!(2, cache.purge(&|k| k.starts_with("/a/")));
+        assert_eq!(0, cache.len());
+    }
+
+    #[test]
+    fn should_count_hits() {
+        let cache = ResponseCache::with_capacity(1);
+        let key: Arc<str> = Arc::from("1");
+        cache.put(
+            key.clone(),
+            dummy_resp(),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        cache.get(&key);
+        cache.get(&key);
+
+        let info = cache.entry("1").unwrap();
+        assert_eq!(2, info.hits);
+        assert_eq!(StatusCode::OK, info.status_code);
+        assert_eq!(1, cache.entries().len());
+    }
+
+    #[test]
+    fn should_not_expire_value_put_again() {
+        let cache = ResponseCache::with_capacity(2);
+        let key: Arc<str> = Arc::from("1");
+        cache.put(key.clone(), dummy_resp(), SystemTime::now());
+        cache.put(
+            key.clone(),
+            dummy_resp(),
+            SystemTime::now() + Duration::from_secs(60),
+        );
+        assert_eq!(0, cache.remove_expired());
+        assert!(cache.get(&key).is_some());
+    }
+
+    #[test]
+    fn should_share_capacity_between_shards() {
+        assert_eq!(1, ResponseCache::with_capacity(3).shards.len());
+        assert_eq!(16, ResponseCache::with_capacity(10_000).shards.len());
+
+        // keys of any shard are stored until capacity is reached
+        let cache = ResponseCache::with_shards(4, 4);
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        for i in 0..4 {
+            assert!(cache.put(Arc::from(i.to_string()), dummy_resp(), ttl));
+        }
+        assert!(!cache.put(Arc::from("4"), dummy_resp(), ttl));
+        assert_eq!(4, cache.len());
+        assert_eq!(4, cache.entries().len());
+
+        assert!(cache.remove("0"));
+        assert_eq!(2, cache.purge(&|k| k != "1"));
+        assert!(cache.put(Arc::from("4"), dummy_resp(), ttl));
+        assert!(cache.put(Arc::from("5"), dummy_resp(), ttl));
+        assert!(cache.put(Arc::from("6"), dummy_resp(), ttl));
+        assert!(!cache.put(Arc::from("7"), dummy_resp(), ttl));
+    }
+
+    #[test]
+    fn should_reclaim_expired_when_capacity_is_reached() {
+        let cache = ResponseCache::with_capacity(1);
+        let first_key: Arc<str> = Arc::from("1");
+        let second_key: Arc<str> = Arc::from("2");
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        assert!(cache.put(first_key.clone(), dummy_resp(), SystemTime::now()));
+        assert!(cache.put(second_key.clone(), dummy_resp(), ttl));
+        // put again over full capacity
+        assert!(cache.put(second_key.clone(), dummy_resp(), ttl));
+        assert_eq!(1, cache.len());
+        assert!(cache.get(&first_key).is_none());
+        assert!(cache.get(&second_key).is_some());
+    }
+
+    #[test]
+    fn should_reclaim_expired_at_most_once_per_interval() {
+        let cache = ResponseCache::with_capacity(1);
+        assert!(cache.put(Arc::from("1"), dummy_resp(), SystemTime::now()));
+        assert!(cache.put(Arc::from("2"), dummy_resp(), SystemTime::now()));
+        // expired entry is left to the sweeper
+        assert!(!cache.put(Arc::from("3"), dummy_resp(), SystemTime::now()));
+        assert_eq!(1, cache.remove_expired());
+        assert!(cache.put(Arc::from("3"), dummy_resp(), SystemTime::now()));
+    }
+
+    #[test]
+    fn should_spill_least_recently_used_to_disk() {
+        let dir = std::env::temp_dir().join(format!("roxy-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        let cache = ResponseCache::with_capacity(1).with_disk(DiskCache::open(&dir, 1024).unwrap());
+        let first_key: Arc<str> = Arc::from("1");
+        let second_key: Arc<str> = Arc::from("2");
+        let mut first = dummy_resp();
+        first.body = Bytes::from("first").into();
+
+        assert!(cache.put(first_key.clone(), first, ttl));
+        assert!(cache.put(second_key.clone(), dummy_resp(), ttl));
+        assert_eq!(1, cache.len());
+
+        let spilled = cache.get(&first_key).unwrap();
+        assert!(matches!(spilled.body, CachedBody::File(_)));
+        assert_eq!(Bytes::from("first"), spilled.body.load().unwrap());
+        assert_eq!(2, cache.entries().len());
+        assert_eq!(0, cache.stats().evictions);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_spill_entry_not_accessed_since_queued() {
+        let dir = std::env::temp_dir().join(format!("roxy-lru-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        let cache = ResponseCache::with_capacity(2).with_disk(DiskCache::open(&dir, 1024).unwrap());
+
+        cache.put(Arc::from("1"), dummy_resp(), ttl);
+        cache.put(Arc::from("2"), dummy_resp(), ttl);
+        cache.get("1");
+        cache.put(Arc::from("3"), dummy_resp(), ttl);
+        cache.put(Arc::from("4"), dummy_resp(), ttl);
+
+        let in_memory = |k: &str| cache.shards[0].read_lock().map.contains_key(k);
+        assert!(!in_memory("1"));
+        assert!(!in_memory("2"));
+        assert!(in_memory("3"));
+        assert!(in_memory("4"));
+        assert!(cache.get("2").is_some());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn should_spill_from_other_shard_when_own_one_is_empty() {
+        let dir = std::env::temp_dir().join(format!("roxy-shards-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let ttl = SystemTime::now() + Duration::from_secs(60);
+        let cache =
+            ResponseCache::with_shards(2, 2).with_disk(DiskCache::open(&dir, 1024).unwrap());
+        let shard_of = |k: &str| std::ptr::eq(cache.shard(k), &cache.shards[0]);
+        let mut keys = (0..).map(|i| i.to_string());
+        let full = keys
+            .by_ref()
+            .filter(|k| shard_of(k))
+            .take(2)
+            .collect::<Vec<String>>();
+        let empty = keys.find(|k| !shard_of(k)).unwrap();
+
+        for k in &full {
+            assert!(cache.put(Arc::from(k.as_str()), dummy_resp(), ttl));
+        }
+        assert!(cache.put(Arc::from(empty.as_str()), dummy_resp(), ttl));
+        assert_eq!(2, cache.len());
+        assert_eq!(0, cache.stats().rejected_inserts);
+        assert!(matches!(
+            cache.get(&full[0]).unwrap().body,
+            CachedBody::File(_)
+        ));
+        assert!(matches!(
+            cache.get(&full[1]).unwrap().body,
+            CachedBody::Bytes(_)
+        ));
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     fn dummy_resp() -> CachedResponse {
         CachedResponse {
             status_code: StatusCode::OK,
             headers: HeaderMap::new(),
-            body: Bytes::new(),
-            ttl: Instant::now(),
+            body: Bytes::new().into(),
+            ttl: SystemTime::now(),
         }
     }
 }
@@ -1,4 +1,14 @@
+pub use self::disk_cache::DiskCache;
+pub use self::expiring_cache::CacheEntryInfo;
+pub use self::expiring_cache::CachedBody;
 pub use self::expiring_cache::CachedResponse;
+pub use self::expiring_cache::FileBody;
 pub use self::expiring_cache::ResponseCache;
+pub use self::storage::{CacheStats, CacheStorage};
+pub use self::sweeper::Sweeper;
 
+mod disk_cache;
 mod expiring_cache;
+mod snapshot;
+mod storage;
+mod sweeper;
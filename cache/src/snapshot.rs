@@ -0,0 +1,138 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use actix_web::web::Bytes;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::disk_cache::EntryMeta;
+use crate::{CacheStorage, CachedResponse, ResponseCache};
+
+// snapshot file is a version followed by serialized entries until the end of file
+const SNAPSHOT_VERSION: u32 = 1;
+const TMP_EXT: &str = "tmp";
+
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    meta: EntryMeta,
+    body: Vec<u8>,
+}
+
+impl ResponseCache {
+    /// Writes live in-memory entries to the snapshot file, returns number of written entries.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension(TMP_EXT);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+
+        let mut saved = 0;
+        for (key, response, expire_at) in self.memory_entries() {
+            let body = match response.body.load() {
+                Ok(body) => body,
+                Err(e) => {
+                    warn!("Skipping {} in cache snapshot. Err = {}", &key, e);
+                    continue;
+                }
+            };
+            let entry = SnapshotEntry {
+                meta: EntryMeta::new(&key, &response, expire_at),
+                body: body.to_vec(),
+            };
+            bincode::serialize_into(&mut writer, &entry).map_err(to_io_error)?;
+            saved += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp_path, path)?;
+        info!("Saved {} cache entries to {:?}", saved, path);
+        Ok(saved)
+    }
+
+    /// Restores non expired entries from the snapshot file, returns number of restored entries.
+    pub fn load_snapshot<P: AsRef<Path>>(&self, path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut version = [0; 4];
+        reader.read_exact(&mut version)?;
+        if u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+            return Err(io::Error::other("unsupported cache snapshot version"));
+        }
+
+        let mut loaded = 0;
+        while let Some(entry) = read_entry(&mut reader)? {
+            let expire_at = entry.meta.expire_at();
+            if expire_at <= SystemTime::now() {
+                continue;
+            }
+            let response = CachedResponse {
+                status_code: entry.meta.status_code()?,
+                headers: entry.meta.headers(),
+                body: Bytes::from(entry.body).into(),
+                ttl: entry.meta.ttl(),
+            };
+            if self.put(Arc::from(entry.meta.key()), response, expire_at) {
+                loaded += 1;
+            }
+        }
+        info!("Loaded {} cache entries from {:?}", loaded, path);
+        Ok(loaded)
+    }
+}
+
+// clean end of file ends the snapshot, truncated entry is an error
+fn read_entry<R: Read>(reader: &mut R) -> io::Result<Option<SnapshotEntry>> {
+    match bincode::deserialize_from(reader) {
+        Ok(entry) => Ok(Some(entry)),
+        Err(e) => match *e {
+            bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(None),
+            e => Err(to_io_error(e)),
+        },
+    }
+}
+
+fn to_io_error<E: ToString>(e: E) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::http::header::CONTENT_TYPE;
+    use actix_web::http::{HeaderMap, HeaderValue, StatusCode};
+    use actix_web::web::Bytes;
+
+    use crate::{CacheStorage, CachedResponse, ResponseCache};
+
+    #[test]
+    fn should_restore_live_entries_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("roxy-snapshot-{}", std::process::id()));
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        let response = CachedResponse {
+            status_code: StatusCode::OK,
+            headers,
+            body: Bytes::from("body").into(),
+            ttl: SystemTime::now() + Duration::from_secs(30),
+        };
+
+        let cache = ResponseCache::with_capacity(2);
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        cache.put(Arc::from("/live"), response.clone(), expire_at);
+        cache.put(Arc::from("/expired"), response, SystemTime::now());
+        assert_eq!(1, cache.save_snapshot(&path).unwrap());
+
+        let restored = ResponseCache::with_capacity(2);
+        assert_eq!(1, restored.load_snapshot(&path).unwrap());
+        let entry = restored.get("/live").unwrap();
+        assert_eq!(Bytes::from("body"), entry.body.load().unwrap());
+        assert_eq!("text/plain", entry.headers.get(CONTENT_TYPE).unwrap());
+        assert!(restored.get("/expired").is_none());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::Serialize;
+
+use crate::{CacheEntryInfo, CachedResponse};
+
+/// Current cache footprint and cumulative counters since startup.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    // body bytes currently cached
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // live entries dropped from cache to make room for new ones, spilling to disk isn't one
+    pub evictions: u64,
+    pub expirations: u64,
+    pub rejected_inserts: u64,
+    // body bytes of all successful inserts
+    pub bytes_stored: u64,
+}
+
+/// Response cache backend used by the proxy.
+/// Entries put in storage shouldn't be served after `expire_at`,
+/// response `ttl` only marks it as stale.
+pub trait CacheStorage: Send + Sync {
+    fn get(&self, k: &str) -> Option<CachedResponse>;
+
+    /// Counts cache hit or miss, called once per request looked up in cache
+    /// since serving a request may take several `get` calls.
+    fn count_lookup(&self, _hit: bool) {}
+
+    fn put(&self, k: Arc<str>, v: CachedResponse, expire_at: SystemTime) -> bool;
+
+    fn remove(&self, k: &str) -> bool;
+
+    fn purge(&self, predicate: &dyn Fn(&str) -> bool) -> usize;
+
+    fn entry(&self, k: &str) -> Option<CacheEntryInfo>;
+
+    fn entries(&self) -> Vec<CacheEntryInfo>;
+
+    fn stats(&self) -> CacheStats;
+}
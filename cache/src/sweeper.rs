@@ -0,0 +1,124 @@
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::{debug, error};
+
+use crate::ResponseCache;
+
+/// Periodically removes expired cache entries on a background thread until stopped or dropped.
+pub struct Sweeper {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    handle: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Sweeper {
+    pub fn start(cache: Arc<ResponseCache>, interval: Duration) -> io::Result<Self> {
+        Self::spawn(interval, move || {
+            let removed = cache.remove_expired();
+            if removed > 0 {
+                debug!("Removed {} expired cache entries", removed);
+            }
+        })
+    }
+
+    fn spawn<F>(interval: Duration, sweep: F) -> io::Result<Self>
+    where
+        F: Fn() + Send + 'static,
+    {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let state = stopped.clone();
+        let handle = thread::Builder::new()
+            .name("cache-sweeper-thread".into())
+            .spawn(move || Self::run(interval, state, sweep))?;
+
+        Ok(Sweeper {
+            stopped,
+            handle: Mutex::new(Some(handle)),
+        })
+    }
+
+    /// Signals sweeper thread to stop and waits for it to finish.
+    pub fn stop(&self) {
+        let (lock, cvar) = &*self.stopped;
+        *lock.lock().expect("Sweeper lock poisoned!") = true;
+        cvar.notify_all();
+
+        let handle = self.handle.lock().expect("Sweeper lock poisoned!").take();
+        if let Some(handle) = handle {
+            if handle.join().is_err() {
+                error!("Cache sweeper thread panicked");
+            }
+        }
+    }
+
+    fn run<F>(interval: Duration, state: Arc<(Mutex<bool>, Condvar)>, sweep: F)
+    where
+        F: Fn(),
+    {
+        let (lock, cvar) = &*state;
+        let mut stopped = lock.lock().expect("Sweeper lock poisoned!");
+        while !*stopped {
+            let (guard, timeout) = cvar
+                .wait_timeout(stopped, interval)
+                .expect("Sweeper lock poisoned!");
+            stopped = guard;
+            if timeout.timed_out() {
+                sweep();
+            }
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::http::{HeaderMap, StatusCode};
+    use actix_web::web::Bytes;
+
+    use crate::{CacheStorage, CachedResponse, ResponseCache, Sweeper};
+
+    #[test]
+    fn should_sweep_expired_entries_until_stopped() {
+        let cache = Arc::new(ResponseCache::with_capacity(2));
+        let (swept, sweeps) = channel();
+        let sweeper_cache = cache.clone();
+        let sweeper = Sweeper::spawn(Duration::from_millis(1), move || {
+            sweeper_cache.remove_expired();
+            let _ = swept.send(());
+        })
+        .unwrap();
+
+        cache.put(Arc::from("1"), dummy_resp(), SystemTime::now());
+        // sweeps which started before the put may not have seen the entry
+        while cache.stats().entries > 0 {
+            sweeps
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Sweeper stopped sweeping");
+        }
+
+        sweeper.stop();
+        while sweeps.try_recv().is_ok() {}
+        // sender is dropped together with the stopped thread
+        assert!(sweeps.recv().is_err());
+    }
+
+    fn dummy_resp() -> CachedResponse {
+        CachedResponse {
+            status_code: StatusCode::OK,
+            headers: HeaderMap::new(),
+            body: Bytes::new().into(),
+            ttl: SystemTime::now(),
+        }
+    }
+}
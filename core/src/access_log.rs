@@ -0,0 +1,426 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::body::{BodySize, MessageBody, ResponseBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderMap, HeaderName, CONTENT_LENGTH, REFERER, USER_AGENT};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use anyhow::anyhow;
+use futures::future::{ok, LocalBoxFuture, Ready};
+use log::warn;
+use serde_json::{json, Value};
+use time::{Format, OffsetDateTime};
+
+use crate::cache_status::CacheOutcome;
+use crate::config::{AccessLog, AccessLogField, AccessLogFormat};
+use crate::forwarded::TrustedProxies;
+use crate::log::open_append;
+use crate::proxy::{Route, Upstream};
+
+const CLF_TIME_FORMAT: &str = "%d/%b/%Y:%H:%M:%S %z";
+
+/// Middleware writing a line per response to the access log once its body is sent.
+#[derive(Clone)]
+pub struct AccessLogger {
+    config: Arc<AccessLog>,
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    trusted_proxies: TrustedProxies,
+    request_id_header: HeaderName,
+}
+
+impl AccessLogger {
+    pub fn new(
+        config: AccessLog,
+        trusted_proxies: TrustedProxies,
+        request_id_header: &str,
+    ) -> anyhow::Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(
+                open_append(path)
+                    .map_err(|e| anyhow!("Can't open access log {}. Err = {}", path, e))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+        Self::with_writer(config, trusted_proxies, request_id_header, writer)
+    }
+
+    /// Reopens access log file, e.g. after it was moved by logrotate.
+    pub fn reopen(&self) -> io::Result<()> {
+        if let Some(path) = &self.config.path {
+            let file = open_append(path)?;
+            *self.writer.lock().expect("access log lock poisoned!") = Box::new(file);
+        }
+        Ok(())
+    }
+
+    fn with_writer(
+        config: AccessLog,
+        trusted_proxies: TrustedProxies,
+        request_id_header: &str,
+        writer: Box<dyn Write + Send>,
+    ) -> anyhow::Result<Self> {
+        let request_id_header = HeaderName::try_from(request_id_header).map_err(|e| {
+            anyhow!(
+                "Invalid request id header {}. Err = {}",
+                request_id_header,
+                e
+            )
+        })?;
+        Ok(AccessLogger {
+            config: Arc::new(config),
+            writer: Arc::new(Mutex::new(writer)),
+            trusted_proxies,
+            request_id_header,
+        })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let line = match self.config.format {
+            AccessLogFormat::Common => entry.common(),
+            AccessLogFormat::Combined => entry.combined(),
+            AccessLogFormat::Json => entry.json(&self.config.fields),
+        };
+        let mut writer = self.writer.lock().expect("access log lock poisoned!");
+        if let Err(e) = writeln!(writer, "{}", line) {
+            warn!("Error writing access log. Err = {}", e);
+        }
+    }
+}
+
+impl<S, B> Transform<S> for AccessLogger
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<AccessLogBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = AccessLogMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(AccessLogMiddleware {
+            service,
+            logger: self.clone(),
+        })
+    }
+}
+
+pub struct AccessLogMiddleware<S> {
+    service: S,
+    logger: AccessLogger,
+}
+
+impl<S, B> Service for AccessLogMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: MessageBody + Unpin + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<AccessLogBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: ServiceRequest) -> Self::Future {
+        let mut entry = Entry::from_request(&req, &self.logger);
+        let logger = self.logger.clone();
+        let res = self.service.call(req);
+        Box::pin(async move {
+            let res = res.await?;
+            let response = res.response();
+            entry.status = response.status().as_u16();
+            entry.route = Route::get(response);
+            entry.upstream = Upstream::get(response);
+            entry.cache_status = CacheOutcome::get(response).map(|o| o.status.as_str());
+            Ok(res.map_body(move |_, body| {
+                ResponseBody::Body(AccessLogBody {
+                    body,
+                    entry,
+                    logger,
+                })
+            }))
+        })
+    }
+}
+
+/// Response body counting sent bytes, access log line is written when it's dropped.
+pub struct AccessLogBody<B> {
+    body: ResponseBody<B>,
+    entry: Entry,
+    logger: AccessLogger,
+}
+
+impl<B: MessageBody + Unpin> MessageBody for AccessLogBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.body).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => {
+                this.entry.bytes_out += chunk.len() as u64;
+                Poll::Ready(Some(Ok(chunk)))
+            }
+            poll => poll,
+        }
+    }
+}
+
+impl<B> Drop for AccessLogBody<B> {
+    fn drop(&mut self) {
+        self.entry.duration = self.entry.started.elapsed();
+        self.logger.write(&self.entry);
+    }
+}
+
+struct Entry {
+    time: OffsetDateTime,
+    started: Instant,
+    duration: Duration,
+    client_ip: Option<IpAddr>,
+    method: String,
+    uri: String,
+    protocol: String,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    route: Option<Route>,
+    upstream: Option<Upstream>,
+    cache_status: Option<&'static str>,
+    request_id: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+}
+
+impl Entry {
+    fn from_request(req: &ServiceRequest, logger: &AccessLogger) -> Self {
+        let headers = req.headers();
+        Entry {
+            time: OffsetDateTime::now_utc(),
+            started: Instant::now(),
+            duration: Duration::default(),
+            client_ip: logger
+                .trusted_proxies
+                .client_ip(req.peer_addr().map(|addr| addr.ip()), headers),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            protocol: format!("{:?}", req.version()),
+            status: 0,
+            bytes_in: header(headers, CONTENT_LENGTH)
+                .and_then(|len| len.parse().ok())
+                .unwrap_or(0),
+            bytes_out: 0,
+            route: None,
+            upstream: None,
+            cache_status: None,
+            request_id: header(headers, logger.request_id_header.clone()),
+            referer: header(headers, REFERER),
+            user_agent: header(headers, USER_AGENT),
+        }
+    }
+
+    // client - - [time] "request line" status bytes
+    fn common(&self) -> String {
+        format!(
+            r#"{} - - [{}] "{} {} {}" {} {}"#,
+            self.client_ip
+                .map_or_else(|| "-".to_string(), |ip| ip.to_string()),
+            self.time.format(CLF_TIME_FORMAT),
+            self.method,
+            self.uri,
+            self.protocol,
+            self.status,
+            match self.bytes_out {
+                0 => "-".to_string(),
+                bytes => bytes.to_string(),
+            }
+        )
+    }
+
+    fn combined(&self) -> String {
+        format!(
+            r#"{} "{}" "{}""#,
+            self.common(),
+            self.referer.as_deref().unwrap_or("-"),
+            self.user_agent.as_deref().unwrap_or("-")
+        )
+    }
+
+    // fields are written in configured order, missing values are null
+    fn json(&self, fields: &[AccessLogField]) -> String {
+        let fields = fields
+            .iter()
+            .map(|field| {
+                let (name, value) = self.field(*field);
+                format!("{}:{}", json!(name), value)
+            })
+            .collect::<Vec<String>>();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn field(&self, field: AccessLogField) -> (&'static str, Value) {
+        match field {
+            AccessLogField::Time => ("time", json!(self.time.format(Format::Rfc3339))),
+            AccessLogField::ClientIp => ("client_ip", json!(self.client_ip)),
+            AccessLogField::Method => ("method", json!(self.method)),
+            AccessLogField::Uri => ("uri", json!(self.uri)),
+            AccessLogField::Protocol => ("protocol", json!(self.protocol)),
+            AccessLogField::Status => ("status", json!(self.status)),
+            AccessLogField::BytesIn => ("bytes_in", json!(self.bytes_in)),
+            AccessLogField::BytesOut => ("bytes_out", json!(self.bytes_out)),
+            AccessLogField::DurationMs => ("duration_ms", json!(millis(self.duration))),
+            AccessLogField::Route => ("route", json!(self.route.as_ref().map(|r| &r.path))),
+            AccessLogField::Group => ("group", json!(self.route.as_ref().map(|r| &r.group))),
+            AccessLogField::Upstream => ("upstream", json!(self.upstream.as_ref().map(|u| &u.url))),
+            AccessLogField::UpstreamLatencyMs => (
+                "upstream_latency_ms",
+                json!(self.upstream.as_ref().map(|u| millis(u.latency))),
+            ),
+            AccessLogField::CacheStatus => ("cache_status", json!(self.cache_status)),
+            AccessLogField::RequestId => ("request_id", json!(self.request_id)),
+            AccessLogField::Referer => ("referer", json!(self.referer)),
+            AccessLogField::UserAgent => ("user_agent", json!(self.user_agent)),
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// microsecond precision
+fn millis(duration: Duration) -> f64 {
+    duration.as_micros() as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use actix_web::{test, web, App, HttpResponse};
+    use serde_json::Value;
+
+    use crate::access_log::AccessLogger;
+    use crate::cache_status::{CacheOutcome, CacheStatus};
+    use crate::config::{AccessLog, AccessLogField, AccessLogFormat};
+    use crate::forwarded::TrustedProxies;
+    use crate::proxy::{Route, Upstream};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn proxied() -> HttpResponse {
+        let mut res = HttpResponse::Ok().body("hello");
+        Route {
+            path: "/api/*".to_string(),
+            group: "api".to_string(),
+        }
+        .set(&mut res);
+        Upstream {
+            url: "http://backend:8080/".to_string(),
+            latency: Duration::from_millis(12),
+        }
+        .set(&mut res);
+        CacheOutcome::new(CacheStatus::Miss).set(&mut res);
+        res
+    }
+
+    async fn log_request(config: AccessLog) -> String {
+        let buffer = Buffer::default();
+        let logger = AccessLogger::with_writer(
+            config,
+            TrustedProxies::default(),
+            "X-Request-ID",
+            Box::new(buffer.clone()),
+        )
+        .unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(logger)
+                .route("/api/data", web::post().to(proxied)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/api/data?q=1")
+            .peer_addr("203.0.113.7:51000".parse().unwrap())
+            .header("x-request-id", "abc-123")
+            .header("user-agent", "curl/7.88")
+            .header("content-length", "4")
+            .set_payload("ping")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!("hello", test::read_body(res).await);
+
+        let line = buffer.0.lock().unwrap().clone();
+        String::from_utf8(line).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn should_write_combined_line() {
+        let line = log_request(AccessLog::default()).await;
+        assert!(line.starts_with("203.0.113.7 - - ["));
+        assert!(line.ends_with("] \"POST /api/data?q=1 HTTP/1.1\" 200 5 \"-\" \"curl/7.88\"\n"));
+    }
+
+    #[actix_rt::test]
+    async fn should_write_selected_json_fields() {
+        let line = log_request(AccessLog {
+            format: AccessLogFormat::Json,
+            fields: vec![
+                AccessLogField::Status,
+                AccessLogField::BytesIn,
+                AccessLogField::BytesOut,
+                AccessLogField::Route,
+                AccessLogField::Group,
+                AccessLogField::Upstream,
+                AccessLogField::UpstreamLatencyMs,
+                AccessLogField::CacheStatus,
+                AccessLogField::RequestId,
+                AccessLogField::Referer,
+            ],
+            ..AccessLog::default()
+        })
+        .await;
+        assert!(line.starts_with(r#"{"status":200,"bytes_in":4,"bytes_out":5,"route":"/api/*""#));
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!("api", json["group"]);
+        assert_eq!("http://backend:8080/", json["upstream"]);
+        assert_eq!(12.0, json["upstream_latency_ms"]);
+        assert_eq!("MISS", json["cache_status"]);
+        assert_eq!("abc-123", json["request_id"]);
+        assert_eq!(Value::Null, json["referer"]);
+        assert!(json.get("time").is_none());
+    }
+}
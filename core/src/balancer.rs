@@ -8,7 +8,7 @@ use anyhow::Result;
 use crossbeam::sync::ShardedLock;
 use url::Url;
 
-use crate::config::Configuration;
+use crate::config::{Configuration, Group};
 
 pub struct Balancer {
     config: Arc<Configuration>,
@@ -30,16 +30,19 @@ impl Balancer {
         }
     }
 
-    pub async fn balance(&self, req: &HttpRequest) -> Result<Instance> {
-        let mut group = self.config.find_group(req.path()).await?;
-        let count = self.current_count(group.name);
+    pub async fn find_group(&self, req: &HttpRequest) -> Result<Group> {
+        self.config.find_group(req.path()).await
+    }
+
+    pub fn balance(&self, group: &Group) -> Instance {
+        let count = self.current_count(group.name.clone());
         let len = group.servers.len();
-        let url = group.servers.remove(count.rem_euclid(len));
+        let url = group.servers[count.rem_euclid(len)].clone();
 
-        Ok(Instance {
+        Instance {
             url,
             timeout: group.timeout,
-        })
+        }
     }
 
     fn current_count(&self, group_name: String) -> usize {
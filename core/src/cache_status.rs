@@ -0,0 +1,174 @@
+use std::time::SystemTime;
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+
+const CACHE_NAME: &str = "roxy";
+const X_CACHE: &str = "x-cache";
+const CACHE_STATUS: &str = "cache-status";
+
+/// How response was produced with respect to the response cache.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    // fresh cached response
+    Hit,
+    // cached response served past its freshness, stale-while-revalidate or stale-if-error
+    Stale,
+    // forwarded upstream without a usable cached response
+    Miss,
+    // stale cached response confirmed by upstream with 304
+    Revalidated,
+    // request or route isn't cacheable
+    Bypass,
+}
+
+/// Cache outcome of a proxied request, kept in response extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheOutcome {
+    pub status: CacheStatus,
+    // freshness left in seconds, negative once stale
+    pub ttl: Option<i64>,
+    pub stored: bool,
+    // response of identical in-flight request was shared
+    pub collapsed: bool,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit | CacheStatus::Revalidated => "HIT",
+            CacheStatus::Stale => "STALE",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+impl CacheOutcome {
+    pub fn new(status: CacheStatus) -> Self {
+        CacheOutcome {
+            status,
+            ttl: None,
+            stored: false,
+            collapsed: false,
+        }
+    }
+
+    pub fn cached(status: CacheStatus, ttl: SystemTime) -> Self {
+        let ttl = match ttl.duration_since(SystemTime::now()) {
+            Ok(left) => left.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        };
+        CacheOutcome {
+            status,
+            ttl: Some(ttl),
+            stored: false,
+            collapsed: false,
+        }
+    }
+
+    pub fn stored(mut self, stored: bool) -> Self {
+        self.stored = stored;
+        self
+    }
+
+    pub fn collapsed(mut self, collapsed: bool) -> Self {
+        self.collapsed = collapsed;
+        self
+    }
+
+    /// Cache-Status header member (RFC 9211).
+    pub fn cache_status(&self) -> String {
+        let mut value = String::from(CACHE_NAME);
+        match self.status {
+            CacheStatus::Hit | CacheStatus::Stale => value.push_str("; hit"),
+            CacheStatus::Miss => value.push_str("; fwd=miss"),
+            CacheStatus::Revalidated => value.push_str("; fwd=stale; fwd-status=304"),
+            CacheStatus::Bypass => value.push_str("; fwd=bypass"),
+        }
+        if let Some(ttl) = self.ttl {
+            value.push_str(&format!("; ttl={}", ttl));
+        }
+        if self.stored {
+            value.push_str("; stored");
+        }
+        if self.collapsed {
+            value.push_str("; collapsed");
+        }
+        value
+    }
+
+    pub fn set(self, res: &mut HttpResponse) {
+        res.extensions_mut().insert(self);
+    }
+
+    pub fn get<B>(res: &HttpResponse<B>) -> Option<CacheOutcome> {
+        res.extensions().get::<CacheOutcome>().copied()
+    }
+
+    /// Sets X-Cache and appends to Cache-Status set by caches closer to the origin.
+    pub fn write_headers(&self, res: &mut HttpResponse) {
+        let headers = res.headers_mut();
+        let cache_status = match headers.get(CACHE_STATUS).and_then(|v| v.to_str().ok()) {
+            Some(upstream) => format!("{}, {}", upstream, self.cache_status()),
+            None => self.cache_status(),
+        };
+        headers.insert(
+            HeaderName::from_static(X_CACHE),
+            HeaderValue::from_static(self.status.as_str()),
+        );
+        if let Ok(value) = HeaderValue::from_str(&cache_status) {
+            headers.insert(HeaderName::from_static(CACHE_STATUS), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderValue;
+    use actix_web::HttpResponse;
+
+    use crate::cache_status::{CacheOutcome, CacheStatus};
+
+    #[test]
+    fn should_format_cache_status() {
+        let hit = CacheOutcome {
+            status: CacheStatus::Stale,
+            ttl: Some(-5),
+            stored: false,
+            collapsed: false,
+        };
+        assert_eq!("roxy; hit; ttl=-5", hit.cache_status());
+        assert_eq!(
+            "roxy; fwd=miss; stored",
+            CacheOutcome::new(CacheStatus::Miss)
+                .stored(true)
+                .cache_status()
+        );
+        assert_eq!(
+            "roxy; fwd=miss; collapsed",
+            CacheOutcome::new(CacheStatus::Miss)
+                .collapsed(true)
+                .cache_status()
+        );
+    }
+
+    #[test]
+    fn should_append_to_upstream_cache_status() {
+        let mut res = HttpResponse::Ok()
+            .header("Cache-Status", "origin-cache; hit")
+            .finish();
+        CacheOutcome::new(CacheStatus::Bypass).write_headers(&mut res);
+
+        assert_eq!(
+            Some(&HeaderValue::from_static("BYPASS")),
+            res.headers().get("x-cache")
+        );
+        assert_eq!(
+            Some(&HeaderValue::from_static(
+                "origin-cache; hit, roxy; fwd=bypass"
+            )),
+            res.headers().get("cache-status")
+        );
+    }
+}
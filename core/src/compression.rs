@@ -0,0 +1,429 @@
+use std::io::{self, Write};
+
+use actix_web::body::{Body, BodySize, BodyStream as StreamBody, MessageBody};
+use actix_web::dev::Decompress;
+use actix_web::error::PayloadError;
+use actix_web::http::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH,
+    CONTENT_RANGE, CONTENT_TYPE, ETAG, VARY,
+};
+use actix_web::http::{ContentEncoding, Method, StatusCode};
+use actix_web::web::{self, Bytes};
+use actix_web::{Error, HttpRequest, HttpResponse};
+use brotli2::write::BrotliEncoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::stream::{self, StreamExt};
+
+use crate::config::{Encoding, ResponseCompression};
+use crate::file_body::BodyStream;
+use crate::http_utils::{Headers, Vary, WEAK_ETAG_PREFIX};
+
+const IDENTITY: &str = "identity";
+const ANY: &str = "*";
+const NO_TRANSFORM: &str = "no-transform";
+const BROTLI_QUALITY: u32 = 5;
+const ZSTD_LEVEL: i32 = 3;
+
+// keeps compressed output in memory until it's taken as a body chunk
+enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Br(BrotliEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Br => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    pub fn compress(&self, body: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = StreamEncoder::new(*self)?;
+        let mut compressed = encoder.encode(body)?.to_vec();
+        compressed.extend_from_slice(&encoder.finish()?);
+        Ok(compressed)
+    }
+}
+
+impl StreamEncoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        let encoder = match encoding {
+            Encoding::Gzip => {
+                StreamEncoder::Gzip(GzEncoder::new(Vec::new(), Compression::default()))
+            }
+            Encoding::Br => StreamEncoder::Br(BrotliEncoder::new(Vec::new(), BROTLI_QUALITY)),
+            Encoding::Zstd => {
+                StreamEncoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), ZSTD_LEVEL)?)
+            }
+        };
+        Ok(encoder)
+    }
+
+    fn encode(&mut self, chunk: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            StreamEncoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            StreamEncoder::Br(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+            StreamEncoder::Zstd(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(std::mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        let output = match self {
+            StreamEncoder::Gzip(encoder) => encoder.finish()?,
+            StreamEncoder::Br(encoder) => encoder.finish()?,
+            StreamEncoder::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(output))
+    }
+}
+
+/// Content type matches one of configured types, a type ending with `/` matches all subtypes.
+pub fn is_compressible(headers: &HeaderMap, content_types: &[String]) -> bool {
+    let content_type = match headers.get_header_value(CONTENT_TYPE.as_str()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    content_types.iter().any(|t| {
+        let t = t.to_ascii_lowercase();
+        if t.ends_with('/') {
+            mime.starts_with(&t)
+        } else {
+            mime == t
+        }
+    })
+}
+
+pub fn is_encoded(headers: &HeaderMap) -> bool {
+    headers
+        .get_header_value(CONTENT_ENCODING.as_str())
+        .is_some_and(|coding| !coding.trim().eq_ignore_ascii_case(IDENTITY))
+}
+
+/// Quality client assigned to content coding in Accept-Encoding, None if not acceptable.
+/// Missing Accept-Encoding is treated as identity only.
+pub fn accepted_quality(req_headers: &HeaderMap, coding: &str) -> Option<f32> {
+    let mut any = None;
+    for value in req_headers
+        .get_all(ACCEPT_ENCODING)
+        .filter_map(|v| v.to_str().ok())
+    {
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if name.eq_ignore_ascii_case(coding) {
+                return (quality > 0.0).then_some(quality);
+            }
+            if name == ANY {
+                any = Some(quality);
+            }
+        }
+    }
+    any.filter(|quality| *quality > 0.0)
+}
+
+/// Stored content coding which client doesn't accept and has to be decoded.
+pub fn decoding_for(req_headers: &HeaderMap, res_headers: &HeaderMap) -> Option<ContentEncoding> {
+    let coding = res_headers
+        .get_header_value(CONTENT_ENCODING.as_str())?
+        .trim();
+    let encoding = ContentEncoding::from(coding);
+    match encoding {
+        ContentEncoding::Identity | ContentEncoding::Auto => None,
+        _ if accepted_quality(req_headers, coding).is_some() => None,
+        _ => Some(encoding),
+    }
+}
+
+/// Compresses response body for clients accepting one of configured encodings.
+pub fn compress_response(
+    config: &ResponseCompression,
+    req: &HttpRequest,
+    mut res: HttpResponse,
+) -> HttpResponse {
+    if !is_transformable(config, req, &res) {
+        return res;
+    }
+    let encoding = match negotiate(req.headers(), &config.encodings) {
+        Some(encoding) => encoding,
+        None => return res,
+    };
+
+    let body = res.take_body();
+    let mut res = res.set_body(Body::from_message(StreamBody::new(compress_stream(
+        Box::pin(body),
+        encoding,
+    ))));
+    let weak_etag = to_weak(res.headers());
+    let headers = res.headers_mut();
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    if let Some(weak_etag) = weak_etag {
+        headers.insert(ETAG, weak_etag);
+    }
+    vary_on_encoding(headers);
+    res
+}
+
+// small, partial, already encoded and no-transform responses are sent as they are
+fn is_transformable(config: &ResponseCompression, req: &HttpRequest, res: &HttpResponse) -> bool {
+    let headers = res.headers();
+    let status = res.status();
+    let too_small = match res.body().size() {
+        BodySize::Sized(size) => size < config.min_size as u64,
+        BodySize::Stream => false,
+        _ => true,
+    };
+    req.method() != Method::HEAD
+        && !too_small
+        && status != StatusCode::PARTIAL_CONTENT
+        && !status.is_informational()
+        && !is_encoded(headers)
+        && !headers.contains_key(CONTENT_RANGE)
+        && !has_no_transform(headers)
+        && is_compressible(headers, &config.content_types)
+}
+
+/// Encoding with the highest client quality, configuration order breaks ties.
+pub fn negotiate(req_headers: &HeaderMap, encodings: &[Encoding]) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in encodings {
+        if let Some(quality) = accepted_quality(req_headers, encoding.as_str()) {
+            if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+                best = Some((*encoding, quality));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compressed representation differs from the upstream one so a strong etag becomes weak.
+pub fn to_weak(headers: &HeaderMap) -> Option<HeaderValue> {
+    headers
+        .etag()
+        .filter(|etag| !etag.starts_with(WEAK_ETAG_PREFIX))
+        .and_then(|etag| HeaderValue::from_str(&format!("{}{}", WEAK_ETAG_PREFIX, etag)).ok())
+}
+
+/// Representation depends on client Accept-Encoding.
+pub fn vary_on_encoding(headers: &mut HeaderMap) {
+    let varies = match (&*headers).vary() {
+        Some(Vary::Headers(names)) => names.iter().any(|name| name == "accept-encoding"),
+        Some(Vary::Any) => true,
+        None => false,
+    };
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+fn has_no_transform(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(CACHE_CONTROL)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case(NO_TRANSFORM))
+}
+
+// chunks are compressed on blocking thread pool
+fn compress_stream(body: BodyStream, encoding: Encoding) -> BodyStream {
+    Box::pin(stream::try_unfold(
+        (body, None::<StreamEncoder>, false),
+        move |(mut body, encoder, done)| async move {
+            if done {
+                return Ok(None);
+            }
+            let mut encoder = match encoder {
+                Some(encoder) => encoder,
+                None => StreamEncoder::new(encoding)?,
+            };
+            loop {
+                match body.next().await {
+                    Some(chunk) => {
+                        let chunk = chunk?;
+                        let (returned, output) = web::block(move || {
+                            encoder.encode(&chunk).map(|output| (encoder, output))
+                        })
+                        .await
+                        .map_err(Error::from)?;
+                        encoder = returned;
+                        if !output.is_empty() {
+                            return Ok(Some((output, (body, Some(encoder), false))));
+                        }
+                    }
+                    None => {
+                        let output = web::block(move || encoder.finish())
+                            .await
+                            .map_err(Error::from)?;
+                        return Ok(Some((output, (body, None, true))));
+                    }
+                }
+            }
+        },
+    ))
+}
+
+pub fn decompress(body: BodyStream, encoding: ContentEncoding) -> BodyStream {
+    let payload =
+        body.map(|chunk| chunk.map_err(|e| PayloadError::Io(io::Error::other(e.to_string()))));
+    Box::pin(Decompress::new(payload, encoding).map(|chunk| chunk.map_err(Error::from)))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{
+        HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+        ETAG, VARY,
+    };
+    use actix_web::http::ContentEncoding;
+    use actix_web::rt::System;
+    use actix_web::test::TestRequest;
+    use actix_web::web::{Bytes, BytesMut};
+    use actix_web::HttpResponse;
+    use futures::StreamExt;
+
+    use crate::compression::{
+        accepted_quality, compress_response, decompress, is_compressible, negotiate,
+    };
+    use crate::config::{Encoding, ResponseCompression};
+    use crate::file_body::body_stream;
+
+    #[test]
+    fn should_negotiate_accept_encoding() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, accepted_quality(&headers, "gzip"));
+
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip;q=0.5, br, zstd;q=0"),
+        );
+        assert_eq!(Some(0.5), accepted_quality(&headers, "gzip"));
+        assert_eq!(Some(1.0), accepted_quality(&headers, "br"));
+        assert_eq!(None, accepted_quality(&headers, "zstd"));
+        assert_eq!(None, accepted_quality(&headers, "deflate"));
+
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("*;q=0.1"));
+        assert_eq!(Some(0.1), accepted_quality(&headers, "br"));
+    }
+
+    #[test]
+    fn should_match_compressible_content_types() {
+        let types = vec!["text/".to_string(), "application/json".to_string()];
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        assert!(is_compressible(&headers, &types));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert!(is_compressible(&headers, &types));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert!(!is_compressible(&headers, &types));
+    }
+
+    #[test]
+    fn should_decompress_stored_body() {
+        let body = "compressible ".repeat(100);
+        for (encoding, content_encoding) in [
+            (Encoding::Gzip, ContentEncoding::Gzip),
+            (Encoding::Br, ContentEncoding::Br),
+        ] {
+            let compressed = encoding.compress(body.as_bytes()).unwrap();
+            assert!(compressed.len() < body.len());
+
+            let stream = decompress(
+                body_stream(Bytes::from(compressed).into()),
+                content_encoding,
+            );
+            let decompressed = System::new("test").block_on(stream.fold(
+                BytesMut::new(),
+                |mut acc, chunk| async move {
+                    acc.extend_from_slice(&chunk.unwrap());
+                    acc
+                },
+            ));
+            assert_eq!(body.as_bytes(), &decompressed[..]);
+        }
+    }
+
+    #[test]
+    fn should_prefer_client_quality_then_configured_order() {
+        let encodings = [Encoding::Br, Encoding::Zstd, Encoding::Gzip];
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, negotiate(&headers, &encodings));
+
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("gzip, zstd, br"));
+        assert_eq!(Some(Encoding::Br), negotiate(&headers, &encodings));
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_static("gzip, zstd, br;q=0.8"),
+        );
+        assert_eq!(Some(Encoding::Zstd), negotiate(&headers, &encodings));
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static("deflate"));
+        assert_eq!(None, negotiate(&headers, &encodings));
+    }
+
+    #[test]
+    fn should_compress_response_on_the_fly() {
+        let config = ResponseCompression {
+            encodings: vec![Encoding::Zstd],
+            content_types: vec!["text/".to_string()],
+            min_size: 1024,
+        };
+        let req = TestRequest::with_header(ACCEPT_ENCODING, "zstd").to_http_request();
+        let body = "compressible ".repeat(100);
+
+        let small = HttpResponse::Ok().content_type("text/plain").body("small");
+        let small = compress_response(&config, &req, small);
+        assert!(small.headers().get(CONTENT_ENCODING).is_none());
+
+        let res = HttpResponse::Ok()
+            .content_type("text/plain")
+            .header(ETAG, "\"v1\"")
+            .body(body.clone());
+        let mut res = compress_response(&config, &req, res);
+        let headers = res.headers();
+        assert_eq!("zstd", headers.get(CONTENT_ENCODING).unwrap());
+        assert_eq!("W/\"v1\"", headers.get(ETAG).unwrap());
+        assert_eq!("Accept-Encoding", headers.get(VARY).unwrap());
+        assert!(headers.get(CONTENT_LENGTH).is_none());
+
+        let compressed = System::new("test").block_on(res.take_body().fold(
+            BytesMut::new(),
+            |mut acc, chunk| async move {
+                acc.extend_from_slice(&chunk.unwrap());
+                acc
+            },
+        ));
+        assert_eq!(
+            body.as_bytes(),
+            &zstd::stream::decode_all(&compressed[..]).unwrap()[..]
+        );
+    }
+}
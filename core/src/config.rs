@@ -1,20 +1,21 @@
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::path::Path;
 use std::sync::Arc;
 
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use crossbeam::sync::ShardedLock;
-use log::{debug, error};
+use log::{debug, error, warn, LevelFilter};
 use serde::Deserialize;
 use url::Url;
 
 use crate::file_watcher::FileListener;
+use crate::log::update_levels;
 use crate::matcher::PathMatcher;
+use crate::metrics::Metrics;
 use crate::yaml_utils::yaml_to_struct;
 
-const CONFIG_FILE: &str = "proxy.yaml";
-
 #[derive(Debug, Deserialize, Clone)]
 pub struct Service {
     pub ip: String,
@@ -22,6 +23,12 @@ pub struct Service {
     pub workers: usize,
     pub log_path: Option<String>,
     pub dev_mode: bool,
+    // connections start with PROXY protocol header sent by load balancer
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    // request id taken from this header when sent by client, echoed in responses and forwarded upstream
+    #[serde(default = "request_id_header")]
+    pub request_id_header: String,
 }
 
 impl Default for Service {
@@ -32,13 +39,311 @@ impl Default for Service {
             workers: 6,
             log_path: None,
             dev_mode: true,
+            proxy_protocol: false,
+            request_id_header: request_id_header(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct CacheStore {
+    pub capacity: usize,
+    // lock partitions of in-memory cache, derived from capacity when not set
+    pub shards: Option<usize>,
+    // seconds between removals of expired entries
+    pub sweep_interval: u64,
+    // adds X-Cache and Cache-Status headers to responses
+    pub status_headers: bool,
+    pub disk: Option<DiskStore>,
+    // in-memory entries are saved on shutdown and loaded on startup
+    pub snapshot: Option<String>,
+    // paths or urls requested through the proxy on startup
+    pub warm_up: Vec<String>,
+    pub compression: Option<CacheCompression>,
+    // seconds identical requests wait for the in-flight one before going upstream themselves
+    pub coalesce_timeout: u64,
+}
+
+/// Compressible bodies are stored compressed, clients not accepting the encoding get them decompressed.
+#[derive(Debug, Deserialize, Clone)]
+pub struct CacheCompression {
+    pub encoding: Encoding,
+    #[serde(default = "compressible_types")]
+    pub content_types: Vec<String>,
+    #[serde(default = "min_compress_size")]
+    pub min_size: usize,
+}
+
+/// On the fly compression of responses, routes can opt out.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ResponseCompression {
+    // preferred first when client accepts several with the same quality
+    #[serde(default = "response_encodings")]
+    pub encodings: Vec<Encoding>,
+    #[serde(default = "compressible_types")]
+    pub content_types: Vec<String>,
+    #[serde(default = "min_compress_size")]
+    pub min_size: usize,
+}
+
+/// Spans of proxied requests are exported to OTLP/HTTP collector.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Tracing {
+    // collector traces endpoint, spans are sent as OTLP JSON
+    pub endpoint: String,
+    pub service_name: String,
+    // ratio of new traces which are sampled, requests with traceparent follow caller's decision
+    pub sample_ratio: f64,
+    // seconds between exports of finished spans
+    pub export_interval: u64,
+    // finished spans waiting for export, new spans are dropped when full
+    pub max_queue_size: usize,
+}
+
+/// Application log levels and rotation of log file, levels are changed on config reload.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct Logging {
+    pub level: LevelFilter,
+    // levels of modules and their submodules, e.g. core::proxy or actix_web
+    pub modules: BTreeMap<String, LevelFilter>,
+    // rotate once log file grows over given size
+    pub max_size_mb: Option<u64>,
+    pub rotation: Rotation,
+    // number of rotated files kept
+    pub retention: usize,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+/// Access log of proxied requests, written apart from application log.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct AccessLog {
+    // appended to, stdout when not set
+    pub path: Option<String>,
+    pub format: AccessLogFormat,
+    // fields of json lines in given order
+    pub fields: Vec<AccessLogField>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogField {
+    Time,
+    ClientIp,
+    Method,
+    Uri,
+    Protocol,
+    Status,
+    // request Content-Length
+    BytesIn,
+    // response body bytes sent
+    BytesOut,
+    DurationMs,
+    Route,
+    Group,
+    Upstream,
+    UpstreamLatencyMs,
+    CacheStatus,
+    RequestId,
+    Referer,
+    UserAgent,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    Gzip,
+    Br,
+    Zstd,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: LevelFilter::Info,
+            modules: BTreeMap::new(),
+            max_size_mb: None,
+            rotation: Rotation::Never,
+            retention: 7,
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        AccessLog {
+            path: None,
+            format: AccessLogFormat::Combined,
+            fields: vec![
+                AccessLogField::Time,
+                AccessLogField::ClientIp,
+                AccessLogField::Method,
+                AccessLogField::Uri,
+                AccessLogField::Protocol,
+                AccessLogField::Status,
+                AccessLogField::BytesIn,
+                AccessLogField::BytesOut,
+                AccessLogField::DurationMs,
+                AccessLogField::Route,
+                AccessLogField::Group,
+                AccessLogField::Upstream,
+                AccessLogField::UpstreamLatencyMs,
+                AccessLogField::CacheStatus,
+                AccessLogField::RequestId,
+                AccessLogField::Referer,
+                AccessLogField::UserAgent,
+            ],
+        }
+    }
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Tracing {
+            endpoint: "http://localhost:4318/v1/traces".to_string(),
+            service_name: "roxy".to_string(),
+            sample_ratio: 1.0,
+            export_interval: 5,
+            max_queue_size: 2048,
+        }
+    }
+}
+
+impl Default for CacheStore {
+    fn default() -> Self {
+        CacheStore {
+            capacity: 10_000,
+            shards: None,
+            sweep_interval: 5,
+            status_headers: false,
+            disk: None,
+            snapshot: None,
+            warm_up: Vec::new(),
+            compression: None,
+            coalesce_timeout: 10,
+        }
+    }
+}
+
+fn request_id_header() -> String {
+    "X-Request-ID".to_string()
+}
+
+fn compressible_types() -> Vec<String> {
+    [
+        "text/",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .iter()
+    .map(|t| t.to_string())
+    .collect()
+}
+
+fn min_compress_size() -> usize {
+    1024
+}
+
+fn response_encodings() -> Vec<Encoding> {
+    vec![Encoding::Br, Encoding::Zstd, Encoding::Gzip]
+}
+
+/// Forwarding headers added to upstream requests, all enabled by default.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct ForwardedHeaders {
+    pub x_forwarded_for: bool,
+    pub x_forwarded_proto: bool,
+    pub x_forwarded_host: bool,
+    pub x_forwarded_port: bool,
+    // RFC 7239 Forwarded
+    pub forwarded: bool,
+    pub via: bool,
+}
+
+impl Default for ForwardedHeaders {
+    fn default() -> Self {
+        ForwardedHeaders {
+            x_forwarded_for: true,
+            x_forwarded_proto: true,
+            x_forwarded_host: true,
+            x_forwarded_port: true,
+            forwarded: true,
+            via: true,
         }
     }
 }
 
+/// Header actions of a route or an outbound group, values of add and set are templates
+/// which can refer to {client_ip}, {request_id}, {group} and {upstream}.
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HeaderRules {
+    // applied to upstream requests
+    pub request: HeaderActions,
+    // applied to client responses
+    pub response: HeaderActions,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct HeaderActions {
+    pub add: BTreeMap<String, String>,
+    pub set: BTreeMap<String, String>,
+    pub remove: Vec<String>,
+    // old name to new name
+    pub rename: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DiskStore {
+    pub path: String,
+    pub max_size_mb: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Admin {
+    pub ip: String,
+    pub port: String,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct ProxyProperties {
     pub service: Service,
+    pub admin: Option<Admin>,
+    #[serde(default)]
+    pub cache: CacheStore,
+    pub compression: Option<ResponseCompression>,
+    #[serde(default)]
+    pub forwarding: ForwardedHeaders,
+    // CIDRs or addresses of proxies in front of roxy allowed to report client address
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    pub tracing: Option<Tracing>,
+    #[serde(default)]
+    pub access_log: AccessLog,
+    #[serde(default)]
+    pub logging: Logging,
     pub inbound: Vec<Inbound>,
     pub outbound: Vec<Outbound>,
 }
@@ -47,6 +352,28 @@ pub struct ProxyProperties {
 pub struct Inbound {
     pub path: String,
     pub group: String,
+    #[serde(default)]
+    pub cache: CachePolicy,
+    // on the fly response compression, enabled by default when configured
+    pub compress: Option<bool>,
+    #[serde(default)]
+    pub headers: HeaderRules,
+}
+
+/// Cache policy of a route or an outbound group, route values take precedence.
+/// Durations are in seconds.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CachePolicy {
+    pub enabled: Option<bool>,
+    // used when upstream doesn't send any caching headers
+    pub default_ttl: Option<u64>,
+    pub max_ttl: Option<u64>,
+    pub ignore_no_cache: Option<bool>,
+    // only listed query parameters are part of the cache key, all if not set
+    pub key_query_params: Option<Vec<String>>,
+    pub key_headers: Option<Vec<String>>,
+    pub stale_while_revalidate: Option<u64>,
+    pub stale_if_error: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,6 +381,10 @@ pub struct Outbound {
     pub timeout: Option<u64>,
     pub group: String,
     pub servers: Vec<String>,
+    #[serde(default)]
+    pub cache: CachePolicy,
+    #[serde(default)]
+    pub headers: HeaderRules,
 }
 
 #[derive(Debug)]
@@ -64,6 +395,9 @@ struct ProxyConfig {
 pub struct Configuration {
     proxy_config: ShardedLock<ProxyConfig>,
     matchers: ShardedLock<PathMatcher>,
+    // reloaded when file with this name changes
+    file_name: String,
+    metrics: Arc<Metrics>,
 }
 
 #[derive(Debug, Clone)]
@@ -71,13 +405,19 @@ pub struct Group {
     pub servers: Vec<Url>,
     pub name: String,
     pub timeout: Duration,
+    pub cache_policy: CachePolicy,
+    pub compress: bool,
+    // configured outbound group, name is the matched inbound path
+    pub outbound: String,
+    // outbound group rules followed by route rules, shared by requests of the group
+    pub headers: Arc<[HeaderRules]>,
 }
 
 trait FileName {
     fn file_name_to_str(&self) -> &str;
 }
 
-impl FileName for &PathBuf {
+impl FileName for Path {
     fn file_name_to_str(&self) -> &str {
         self.file_name()
             .as_ref()
@@ -86,12 +426,80 @@ impl FileName for &PathBuf {
     }
 }
 
+impl CachePolicy {
+    pub fn merge(&self, other: &CachePolicy) -> CachePolicy {
+        CachePolicy {
+            enabled: self.enabled.or(other.enabled),
+            default_ttl: self.default_ttl.or(other.default_ttl),
+            max_ttl: self.max_ttl.or(other.max_ttl),
+            ignore_no_cache: self.ignore_no_cache.or(other.ignore_no_cache),
+            key_query_params: self
+                .key_query_params
+                .clone()
+                .or_else(|| other.key_query_params.clone()),
+            key_headers: self
+                .key_headers
+                .clone()
+                .or_else(|| other.key_headers.clone()),
+            stale_while_revalidate: self.stale_while_revalidate.or(other.stale_while_revalidate),
+            stale_if_error: self.stale_if_error.or(other.stale_if_error),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.unwrap_or(true)
+    }
+
+    pub fn ignore_no_cache(&self) -> bool {
+        self.ignore_no_cache.unwrap_or(false)
+    }
+}
+
+impl ProxyProperties {
+    // warm up requests carry no PROXY protocol header so the listener would reject them
+    fn warm_up_urls(&self) -> Vec<String> {
+        if self.service.proxy_protocol && !self.cache.warm_up.is_empty() {
+            warn!("Cache warm up skipped, listener expects PROXY protocol header");
+            return Vec::new();
+        }
+        self.cache.warm_up.clone()
+    }
+
+    // rejects values which would only fail once used
+    fn validate(&self) -> Result<()> {
+        if self.cache.sweep_interval == 0 {
+            bail!("cache sweep_interval has to be at least 1 second");
+        }
+        if self.cache.shards == Some(0) {
+            bail!("cache shards has to be at least 1");
+        }
+        if self.service.proxy_protocol && self.trusted_proxies.is_empty() {
+            bail!("service proxy_protocol requires load balancer address in trusted_proxies");
+        }
+        let header_rules = self
+            .inbound
+            .iter()
+            .map(|i| &i.headers)
+            .chain(self.outbound.iter().map(|o| &o.headers));
+        for rules in header_rules {
+            rules.validate()?;
+        }
+        if let Some(tracing) = &self.tracing {
+            if tracing.export_interval == 0 {
+                bail!("tracing export_interval has to be at least 1 second");
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Configuration {
     pub fn new<P>(path: P) -> Result<Self>
     where
         P: AsRef<Path>,
     {
-        let props = yaml_to_struct(&path)?;
+        let props: ProxyProperties = yaml_to_struct(&path)?;
+        props.validate()?;
         debug!("Loaded props {:?}", &props);
         let path_matchers = PathMatcher::new(&props)?;
         debug!("Path matchers {:?}", &path_matchers);
@@ -100,9 +508,17 @@ impl Configuration {
         Ok(Configuration {
             proxy_config: ShardedLock::new(ProxyConfig { props }),
             matchers: path_matchers,
+            file_name: path.as_ref().file_name_to_str().to_string(),
+            metrics: Arc::new(Metrics::new()),
         })
     }
 
+    /// Counts config reloads in given metrics.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
     pub async fn find_group(&self, req_path: &str) -> Result<Group> {
         self.matchers
             .read()
@@ -119,12 +535,95 @@ impl Configuration {
             .clone()
     }
 
-    fn interested(&self, file_name: &str) -> bool {
-        CONFIG_FILE == file_name
+    pub fn cache_store(&self) -> CacheStore {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .cache
+            .clone()
+    }
+
+    pub fn warm_up_urls(&self) -> Vec<String> {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .warm_up_urls()
+    }
+
+    pub fn compression_config(&self) -> Option<ResponseCompression> {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .compression
+            .clone()
+    }
+
+    pub fn forwarding_config(&self) -> ForwardedHeaders {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .forwarding
+            .clone()
+    }
+
+    pub fn trusted_proxies(&self) -> Vec<String> {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .trusted_proxies
+            .clone()
+    }
+
+    pub fn tracing_config(&self) -> Option<Tracing> {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .tracing
+            .clone()
+    }
+
+    pub fn logging_config(&self) -> Logging {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .logging
+            .clone()
+    }
+
+    pub fn access_log_config(&self) -> AccessLog {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .access_log
+            .clone()
+    }
+
+    pub fn admin_config(&self) -> Option<Admin> {
+        self.proxy_config
+            .read()
+            .expect("proxy config read lock poisoned!")
+            .props
+            .admin
+            .clone()
+    }
+
+    fn interested(&self, path: &Path) -> bool {
+        self.file_name == path.file_name_to_str()
     }
 
     fn reload_config(&self, path: &Path) {
-        match yaml_to_struct(path) {
+        match yaml_to_struct::<ProxyProperties, _>(path).and_then(|props| {
+            props.validate()?;
+            Ok(props)
+        }) {
             Ok(props) => {
                 debug!(
                     "Reloading properties:\n old: {:?} \n new: {:?}",
@@ -143,17 +642,21 @@ impl Configuration {
                 {
                     Ok(_) => {
                         debug!("Reloaded properties {:?}", &props);
+                        self.metrics.config_reload(true);
+                        update_levels(&props.logging);
                         self.proxy_config
                             .write()
                             .expect("proxy config write lock poisoned!")
                             .props = props;
                     }
                     Err(e) => {
+                        self.metrics.config_reload(false);
                         error!("Error reloading proxy config. Err = {}", e);
                     }
                 }
             }
             Err(e) => {
+                self.metrics.config_reload(false);
                 error!("Error loading proxy config. Err = {}", e);
             }
         }
@@ -162,7 +665,7 @@ impl Configuration {
 
 impl FileListener for Arc<Configuration> {
     fn notify_file_changed(&self, path: &Path) {
-        if !self.interested(path.to_string_lossy().as_ref()) {
+        if !self.interested(path) {
             return;
         }
 
@@ -170,3 +673,77 @@ impl FileListener for Arc<Configuration> {
         self.reload_config(path);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{CachePolicy, ProxyProperties};
+
+    fn props(yaml: &str) -> ProxyProperties {
+        let base = "service: {ip: localhost, port: 8080, workers: 1, dev_mode: true}\n\
+                    inbound: [{path: /app/*, group: app}]\n\
+                    outbound: [{group: app, servers: [http://localhost:8081/]}]\n";
+        serde_yaml::from_str(&format!("{}{}", base, yaml)).unwrap()
+    }
+
+    #[test]
+    fn should_reject_invalid_values() {
+        assert!(props("").validate().is_ok());
+        assert!(props("tracing: {export_interval: 1}").validate().is_ok());
+        assert!(props("tracing: {export_interval: 0}").validate().is_err());
+        assert!(props("cache: {sweep_interval: 0}").validate().is_err());
+        assert!(props("cache: {shards: 0}").validate().is_err());
+        let mut invalid_rules = props("");
+        invalid_rules.outbound[0]
+            .headers
+            .request
+            .set
+            .insert("Bad Name".to_string(), "a".to_string());
+        assert!(invalid_rules.validate().is_err());
+
+        let mut proxy_protocol = props("");
+        proxy_protocol.service.proxy_protocol = true;
+        assert!(proxy_protocol.validate().is_err());
+        proxy_protocol.trusted_proxies = vec!["10.0.0.0/8".to_string()];
+        assert!(proxy_protocol.validate().is_ok());
+    }
+
+    #[test]
+    fn should_skip_warm_up_behind_proxy_protocol() {
+        let mut props = props("cache: {warm_up: [/app/index.html]}");
+        assert_eq!(vec!["/app/index.html".to_string()], props.warm_up_urls());
+
+        props.service.proxy_protocol = true;
+        props.trusted_proxies = vec!["127.0.0.1".to_string()];
+        assert!(props.warm_up_urls().is_empty());
+    }
+
+    #[test]
+    fn should_prefer_route_cache_policy_values() {
+        let route = CachePolicy {
+            default_ttl: Some(10),
+            key_headers: Some(vec!["Accept-Language".to_string()]),
+            ..CachePolicy::default()
+        };
+        let group = CachePolicy {
+            enabled: Some(false),
+            default_ttl: Some(60),
+            max_ttl: Some(600),
+            key_headers: Some(vec![]),
+            stale_if_error: Some(30),
+            ..CachePolicy::default()
+        };
+
+        let merged = route.merge(&group);
+        assert!(!merged.enabled());
+        assert!(!merged.ignore_no_cache());
+        assert_eq!(Some(10), merged.default_ttl);
+        assert_eq!(Some(600), merged.max_ttl);
+        assert_eq!(
+            Some(vec!["Accept-Language".to_string()]),
+            merged.key_headers
+        );
+        assert_eq!(None, merged.key_query_params);
+        assert_eq!(None, merged.stale_while_revalidate);
+        assert_eq!(Some(30), merged.stale_if_error);
+    }
+}
@@ -0,0 +1,45 @@
+use std::io;
+use std::pin::Pin;
+
+use actix_web::dev::SizedStream;
+use actix_web::web::{self, Bytes};
+use actix_web::Error;
+use futures::stream::{self, Stream};
+
+use cache::{CachedBody, FileBody};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+pub type BodyStream = Pin<Box<dyn Stream<Item = Result<Bytes, Error>>>>;
+
+/// Streams cached body from disk, chunks are read on blocking thread pool.
+pub fn file_stream(body: FileBody) -> SizedStream<BodyStream> {
+    SizedStream::new(body.len(), file_chunks(body))
+}
+
+pub fn body_stream(body: CachedBody) -> BodyStream {
+    match body {
+        CachedBody::Bytes(bytes) => Box::pin(stream::once(async { Ok(bytes) })),
+        CachedBody::File(file) => file_chunks(file),
+    }
+}
+
+fn file_chunks(body: FileBody) -> BodyStream {
+    let chunks = stream::try_unfold((body, 0), |(body, read)| async move {
+        if read == body.len() {
+            return Ok(None);
+        }
+        let (chunk, body) = web::block(move || read_chunk(body, read))
+            .await
+            .map_err(Error::from)?;
+        let read = read + chunk.len() as u64;
+        Ok(Some((chunk, (body, read))))
+    });
+    Box::pin(chunks)
+}
+
+fn read_chunk(body: FileBody, read: u64) -> io::Result<(Bytes, FileBody)> {
+    let size = (body.len() - read).min(CHUNK_SIZE);
+    let chunk = body.read(read, size)?;
+    Ok((chunk, body))
+}
@@ -0,0 +1,380 @@
+use std::net::IpAddr;
+
+use actix_web::http::header::{HeaderMap, HeaderName, FORWARDED, HOST, VIA};
+use actix_web::http::Version;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use ipnet::IpNet;
+
+use crate::config::ForwardedHeaders;
+use crate::http_utils::{Headers, XFF_HEADER_NAME};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PORT: &str = "x-forwarded-port";
+const X_REAL_IP: &str = "x-real-ip";
+const VIA_PSEUDONYM: &str = "roxy";
+const UNKNOWN: &str = "unknown";
+const HTTP: &str = "http";
+const HTTPS: &str = "https";
+
+/// Headers through which clients can claim another address, dropped unless sent by trusted proxy.
+pub const CLIENT_ADDRESS_HEADERS: [&str; 6] = [
+    X_FORWARDED_FOR,
+    X_FORWARDED_PROTO,
+    X_FORWARDED_HOST,
+    X_FORWARDED_PORT,
+    X_REAL_IP,
+    "forwarded",
+];
+
+/// Proxies (e.g. load balancers) allowed to report client address in forwarding headers.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Accepts CIDRs and single addresses.
+    pub fn new(proxies: &[String]) -> Result<Self> {
+        let networks = proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse::<IpNet>()
+                    .or_else(|_| proxy.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow!("Invalid trusted proxy {}", proxy))
+            })
+            .collect::<Result<Vec<IpNet>>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Real client address, forwarding chain is walked from the closest hop while hops are trusted.
+    /// Forwarded header takes precedence over X-Forwarded-For.
+    pub fn client_ip(&self, peer: Option<IpAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let mut client = peer?;
+        if !self.trusts(client) {
+            return Some(client);
+        }
+        for hop in forwarding_chain(headers).into_iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = ip;
+                    if !self.trusts(ip) {
+                        break;
+                    }
+                }
+                // obfuscated or unknown node, last trusted hop is the best known address
+                None => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Client side of the connection as seen by the proxy, or as reported by trusted proxy in front of it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientInfo {
+    // immediate peer, trusted proxy or client itself
+    pub peer: Option<IpAddr>,
+    pub client_ip: Option<IpAddr>,
+    pub trusted: bool,
+    pub proto: String,
+    pub host: Option<String>,
+    pub port: u16,
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest, trusted_proxies: &TrustedProxies) -> Self {
+        let headers = req.headers();
+        let peer = req.peer_addr().map(|addr| addr.ip());
+        let trusted = peer.is_some_and(|ip| trusted_proxies.trusts(ip));
+        let reported = |name: &str| {
+            headers
+                .get_header_value(name)
+                .map(|v| v.split(',').next().unwrap_or_default().trim().to_string())
+                .filter(|v| trusted && !v.is_empty())
+        };
+
+        let proto = reported(X_FORWARDED_PROTO).unwrap_or_else(|| {
+            let secure = req.app_config().secure();
+            if secure { HTTPS } else { HTTP }.to_string()
+        });
+        let host = reported(X_FORWARDED_HOST).or_else(|| {
+            headers
+                .get_header_value(HOST.as_str())
+                .map(str::to_string)
+                .or_else(|| req.uri().authority().map(|a| a.to_string()))
+        });
+        let port = reported(X_FORWARDED_PORT)
+            .and_then(|port| port.parse().ok())
+            .or_else(|| host.as_deref().and_then(host_port))
+            .unwrap_or(if proto == HTTPS { 443 } else { 80 });
+
+        ClientInfo {
+            peer,
+            client_ip: trusted_proxies.client_ip(peer, headers),
+            trusted,
+            proto,
+            host,
+            port,
+        }
+    }
+
+    /// Forwarding header values, appended to the ones received from trusted proxy when the header is a list.
+    pub fn headers(
+        &self,
+        config: &ForwardedHeaders,
+        req: &HttpRequest,
+    ) -> Vec<(HeaderName, String)> {
+        let headers = req.headers();
+        let trusted_values = |name: &str| {
+            if self.trusted {
+                received(headers, name)
+            } else {
+                Vec::new()
+            }
+        };
+        let mut forwarding = Vec::new();
+        if config.x_forwarded_for {
+            let peer = self
+                .peer
+                .map_or_else(|| UNKNOWN.to_string(), |ip| ip.to_string());
+            forwarding.push((
+                HeaderName::from_static(X_FORWARDED_FOR),
+                append(trusted_values(XFF_HEADER_NAME), &peer),
+            ));
+        }
+        if config.x_forwarded_proto {
+            forwarding.push((
+                HeaderName::from_static(X_FORWARDED_PROTO),
+                self.proto.clone(),
+            ));
+        }
+        if config.x_forwarded_host {
+            if let Some(host) = &self.host {
+                forwarding.push((HeaderName::from_static(X_FORWARDED_HOST), host.clone()));
+            }
+        }
+        if config.x_forwarded_port {
+            forwarding.push((
+                HeaderName::from_static(X_FORWARDED_PORT),
+                self.port.to_string(),
+            ));
+        }
+        if config.forwarded {
+            let element = self.forwarded_element();
+            forwarding.push((
+                FORWARDED,
+                append(trusted_values(FORWARDED.as_str()), &element),
+            ));
+        }
+        if config.via {
+            let via = format!("{} {}", via_protocol(req.version()), VIA_PSEUDONYM);
+            // Via doesn't identify client so it's kept from any peer
+            forwarding.push((VIA, append(received(headers, VIA.as_str()), &via)));
+        }
+        forwarding
+    }
+
+    // RFC 7239 forwarded-element, ipv6 addresses and hosts with port are quoted
+    fn forwarded_element(&self) -> String {
+        let node = match self.peer {
+            Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+            Some(IpAddr::V4(ip)) => ip.to_string(),
+            None => UNKNOWN.to_string(),
+        };
+        let mut element = format!("for={};proto={}", node, self.proto);
+        if let Some(host) = &self.host {
+            if host.contains(':') {
+                element.push_str(&format!(";host=\"{}\"", host));
+            } else {
+                element.push_str(&format!(";host={}", host));
+            }
+        }
+        element
+    }
+}
+
+// list headers may be split over several header lines
+fn received<'a>(headers: &'a HeaderMap, name: &str) -> Vec<&'a str> {
+    headers
+        .get_all(name)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn append<'a>(mut values: Vec<&'a str>, value: &'a str) -> String {
+    values.push(value);
+    values.join(", ")
+}
+
+// client addresses reported by Forwarded for= or X-Forwarded-For, None for unknown and obfuscated nodes
+fn forwarding_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = received(headers, FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| node_ip(node))
+            })
+            .collect();
+    }
+    received(headers, XFF_HEADER_NAME)
+        .into_iter()
+        .map(node_ip)
+        .collect()
+}
+
+// node may be quoted and carry a port - "[2001:db8::1]:4711", 192.0.2.43:47011
+fn node_ip(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| {
+        let (ip, _port) = node.rsplit_once(':')?;
+        ip.parse().ok()
+    })
+}
+
+fn host_port(host: &str) -> Option<u16> {
+    let (_, port) = host.rsplit_once(':')?;
+    // ipv6 literal without port
+    if port.ends_with(']') {
+        return None;
+    }
+    port.parse().ok()
+}
+
+fn via_protocol(version: Version) -> &'static str {
+    match version {
+        Version::HTTP_09 => "0.9",
+        Version::HTTP_10 => "1.0",
+        Version::HTTP_2 => "2",
+        _ => "1.1",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED, VIA};
+    use actix_web::test::TestRequest;
+
+    use crate::config::ForwardedHeaders;
+    use crate::forwarded::{ClientInfo, TrustedProxies};
+
+    fn trusted(proxies: &[&str]) -> TrustedProxies {
+        let proxies = proxies.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        TrustedProxies::new(&proxies).unwrap()
+    }
+
+    #[test]
+    fn should_append_forwarding_headers_of_trusted_proxy() {
+        let req = TestRequest::default()
+            .peer_addr("192.168.1.10:51000".parse().unwrap())
+            .header("Host", "internal:8080")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .header("X-Forwarded-Proto", "https")
+            .header("X-Forwarded-Host", "example.com")
+            .header(VIA, "1.1 edge")
+            .to_http_request();
+        let client = ClientInfo::from_request(&req, &trusted(&["192.168.1.0/24"]));
+        assert_eq!(Some("10.0.0.1".parse().unwrap()), client.client_ip);
+        assert_eq!(443, client.port);
+
+        let headers = client.headers(&ForwardedHeaders::default(), &req);
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(Some("10.0.0.1, 192.168.1.10"), value("x-forwarded-for"));
+        assert_eq!(Some("https"), value("x-forwarded-proto"));
+        assert_eq!(Some("example.com"), value("x-forwarded-host"));
+        assert_eq!(Some("443"), value("x-forwarded-port"));
+        assert_eq!(
+            Some("for=192.168.1.10;proto=https;host=example.com"),
+            value(FORWARDED.as_str())
+        );
+        assert_eq!(Some("1.1 edge, 1.1 roxy"), value(VIA.as_str()));
+    }
+
+    #[test]
+    fn should_ignore_forwarding_headers_of_untrusted_peer() {
+        let req = TestRequest::default()
+            .peer_addr("[2001:db8::1]:51000".parse().unwrap())
+            .header("Host", "example.com:8443")
+            .header("X-Forwarded-For", "10.0.0.1")
+            .header("X-Forwarded-Proto", "https")
+            .to_http_request();
+        let config = ForwardedHeaders {
+            via: false,
+            ..ForwardedHeaders::default()
+        };
+
+        let client = ClientInfo::from_request(&req, &trusted(&["192.168.1.0/24"]));
+        assert_eq!(Some("2001:db8::1".parse().unwrap()), client.client_ip);
+        let headers = client.headers(&config, &req);
+        let value = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(Some("2001:db8::1"), value("x-forwarded-for"));
+        assert_eq!(Some("8443"), value("x-forwarded-port"));
+        assert_eq!(
+            Some("for=\"[2001:db8::1]\";proto=http;host=\"example.com:8443\""),
+            value(FORWARDED.as_str())
+        );
+        assert_eq!(None, value(VIA.as_str()));
+    }
+
+    #[test]
+    fn should_resolve_client_through_trusted_hops() {
+        let proxies = trusted(&["10.0.0.0/8", "192.168.1.1"]);
+        let peer = Some("192.168.1.1".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("x-forwarded-for"),
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+        );
+        assert_eq!(
+            Some("203.0.113.7".parse().unwrap()),
+            proxies.client_ip(peer, &headers)
+        );
+
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static("for=\"[2001:db8::7]:4711\", for=10.0.0.3;proto=https"),
+        );
+        assert_eq!(
+            Some("2001:db8::7".parse().unwrap()),
+            proxies.client_ip(peer, &headers)
+        );
+
+        headers.insert(
+            FORWARDED,
+            HeaderValue::from_static("for=_hidden, for=10.0.0.3"),
+        );
+        assert_eq!(
+            Some("10.0.0.3".parse().unwrap()),
+            proxies.client_ip(peer, &headers)
+        );
+        assert!(TrustedProxies::new(&["not-a-network".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::convert::TryFrom;
+use std::net::IpAddr;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use anyhow::{anyhow, Result};
+
+use crate::config::{HeaderActions, HeaderRules};
+
+/// Values available to header value templates.
+#[derive(Debug, Default)]
+pub struct TemplateContext<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub request_id: Option<&'a str>,
+    pub group: &'a str,
+    pub upstream: Option<&'a str>,
+}
+
+impl TemplateContext<'_> {
+    // rendered in a single pass so substituted values aren't expanded again,
+    // unknown placeholders are kept as they are, missing values render empty
+    fn render(&self, template: &str) -> String {
+        let mut rendered = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            match &rest[1..end] {
+                "client_ip" => {
+                    if let Some(ip) = self.client_ip {
+                        rendered.push_str(&ip.to_string());
+                    }
+                }
+                "request_id" => rendered.push_str(self.request_id.unwrap_or_default()),
+                "group" => rendered.push_str(self.group),
+                "upstream" => rendered.push_str(self.upstream.unwrap_or_default()),
+                _ => rendered.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+impl HeaderRules {
+    /// Checks header names and values so invalid rules are rejected with the configuration.
+    pub fn validate(&self) -> Result<()> {
+        self.request.validate()?;
+        self.response.validate()
+    }
+}
+
+impl HeaderActions {
+    /// Applies actions in order remove, rename, set and add.
+    pub fn apply(&self, headers: &mut HeaderMap, ctx: &TemplateContext) {
+        for name in &self.remove {
+            headers.remove(name.as_str());
+        }
+        for (from, to) in &self.rename {
+            let to = match header_name(to) {
+                Some(to) => to,
+                None => continue,
+            };
+            let values = headers
+                .get_all(from.as_str())
+                .cloned()
+                .collect::<Vec<HeaderValue>>();
+            headers.remove(from.as_str());
+            for value in values {
+                headers.append(to.clone(), value);
+            }
+        }
+        for (name, value) in &self.set {
+            if let Some((name, value)) = header(name, &ctx.render(value)) {
+                headers.insert(name, value);
+            }
+        }
+        for (name, value) in &self.add {
+            if let Some((name, value)) = header(name, &ctx.render(value)) {
+                headers.append(name, value);
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let names = self
+            .remove
+            .iter()
+            .chain(self.rename.keys())
+            .chain(self.rename.values())
+            .chain(self.set.keys())
+            .chain(self.add.keys());
+        for name in names {
+            HeaderName::try_from(name.as_str())
+                .map_err(|e| anyhow!("Invalid header name {}. Err = {}", name, e))?;
+        }
+        for (name, value) in self.set.iter().chain(self.add.iter()) {
+            HeaderValue::from_str(value)
+                .map_err(|e| anyhow!("Invalid {} header value {}. Err = {}", name, value, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.add.is_empty()
+            && self.set.is_empty()
+            && self.remove.is_empty()
+            && self.rename.is_empty()
+    }
+}
+
+// names and templates are validated with the configuration
+fn header(name: &str, value: &str) -> Option<(HeaderName, HeaderValue)> {
+    let name = header_name(name)?;
+    HeaderValue::from_str(value).ok().map(|value| (name, value))
+}
+
+fn header_name(name: &str) -> Option<HeaderName> {
+    HeaderName::try_from(name).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, SERVER};
+
+    use crate::config::{HeaderActions, HeaderRules};
+    use crate::header_rules::TemplateContext;
+
+    #[test]
+    fn should_apply_header_actions() {
+        let actions: HeaderActions = serde_yaml::from_str(
+            r#"
+            remove: [Server]
+            rename: {X-Old: X-New}
+            set: {X-Client: "{client_ip} via {group}"}
+            add: {X-Upstream: "{upstream}", X-Request: "{request_id}{unknown}"}
+            "#,
+        )
+        .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(SERVER, HeaderValue::from_static("nginx"));
+        headers.insert(
+            HeaderName::from_static("x-old"),
+            HeaderValue::from_static("1"),
+        );
+        headers.insert(
+            HeaderName::from_static("x-client"),
+            HeaderValue::from_static("spoofed"),
+        );
+        let ctx = TemplateContext {
+            client_ip: Some("203.0.113.7".parse().unwrap()),
+            request_id: Some("abc"),
+            group: "api",
+            upstream: Some("http://backend:8080/"),
+        };
+
+        actions.apply(&mut headers, &ctx);
+        assert!(headers.get(SERVER).is_none());
+        assert!(headers.get("x-old").is_none());
+        assert_eq!("1", headers.get("x-new").unwrap());
+        assert_eq!("203.0.113.7 via api", headers.get("x-client").unwrap());
+        assert_eq!("http://backend:8080/", headers.get("x-upstream").unwrap());
+        assert_eq!("abc{unknown}", headers.get("x-request").unwrap());
+    }
+
+    #[test]
+    fn should_not_expand_placeholders_in_substituted_values() {
+        let ctx = TemplateContext {
+            request_id: Some("{group}"),
+            group: "api",
+            ..TemplateContext::default()
+        };
+        assert_eq!("{group}/api{", ctx.render("{request_id}/{group}{"));
+        assert_eq!("/{client_ip", ctx.render("{client_ip}/{client_ip"));
+    }
+
+    #[test]
+    fn should_reject_invalid_header_rules() {
+        let rules = |yaml: &str| serde_yaml::from_str::<HeaderRules>(yaml).unwrap();
+        assert!(rules("request: {set: {X-Client: \"{client_ip}\"}}")
+            .validate()
+            .is_ok());
+        assert!(rules("request: {remove: [Bad Name]}").validate().is_err());
+        assert!(rules("response: {rename: {X-Old: \"X:New\"}}")
+            .validate()
+            .is_err());
+        assert!(rules("response: {add: {X-Value: \"a\\nb\"}}")
+            .validate()
+            .is_err());
+    }
+}
@@ -1,17 +1,61 @@
-use actix_web::http::header::{HeaderMap, CACHE_CONTROL};
+use std::time::SystemTime;
+
+use actix_web::http::header::{
+    HeaderMap, HttpDate, CACHE_CONTROL, CONNECTION, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+    LAST_MODIFIED, PRAGMA, VARY,
+};
 use actix_web::http::Method;
 use actix_web::HttpRequest;
 
 pub const XFF_HEADER_NAME: &str = "X-Forwarded-For";
 const EMPTY: &str = "";
-const EMPTY_STR_VEC: Vec<&str> = vec![];
+const PUBLIC: &str = "public";
+const PRIVATE: &str = "private";
+const NO_CACHE: &str = "no-cache";
+const NO_STORE: &str = "no-store";
+const MAX_AGE: &str = "max-age";
+const STALE_WHILE_REVALIDATE: &str = "stale-while-revalidate";
+const STALE_IF_ERROR: &str = "stale-if-error";
+const VARY_ANY: &str = "*";
+const ETAG_ANY: &str = "*";
+pub const WEAK_ETAG_PREFIX: &str = "W/";
+// RFC 7230 section 6.1, Proxy-Connection is a non standard one still sent by some clients
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Vary {
+    Headers(Vec<String>),
+    Any,
+}
 
 pub trait Headers {
     fn get_header_value(&self, name: &str) -> Option<&str>;
 
-    fn xff(&self) -> Option<&str>;
-
     fn max_age(&self) -> Option<u64>;
+
+    fn no_cache(&self) -> bool;
+
+    fn no_store(&self) -> bool;
+
+    fn stale_while_revalidate(&self) -> Option<u64>;
+
+    fn stale_if_error(&self) -> Option<u64>;
+
+    fn vary(&self) -> Option<Vary>;
+
+    fn etag(&self) -> Option<&str>;
+
+    fn last_modified(&self) -> Option<SystemTime>;
+
+    fn has_validators(&self) -> bool;
 }
 
 impl Headers for &HeaderMap {
@@ -26,54 +70,149 @@ impl Headers for &HeaderMap {
         }
     }
 
-    fn xff(&self) -> Option<&str> {
-        self.get_header_value(XFF_HEADER_NAME)
-    }
-
     fn max_age(&self) -> Option<u64> {
-        let mut max_age = None;
-        let mut public = false;
-
-        self.get_all(CACHE_CONTROL)
-            .into_iter()
-            .flat_map(|header_value| match header_value.to_str() {
-                Ok(s) => s.split(',').collect(),
-                Err(_) => EMPTY_STR_VEC,
-            })
-            .for_each(|split| {
-                if split == "public" {
-                    public = true;
-                }
-
-                let kv_pair: Vec<&str> = split.split('=').collect();
-                if kv_pair.len() == 2 && kv_pair[0] == "max-age" {
-                    max_age = match kv_pair[1].parse() {
-                        Ok(value) => Some(value),
-                        Err(_) => None,
-                    };
-                }
-            });
-
-        if public {
-            max_age
+        let directives = cache_directives(self);
+        if has_directive(&directives, PUBLIC) {
+            directive_seconds(&directives, MAX_AGE)
         } else {
             None
         }
     }
+
+    fn no_cache(&self) -> bool {
+        let pragma_no_cache = self
+            .get_all(PRAGMA)
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.trim().eq_ignore_ascii_case(NO_CACHE));
+        pragma_no_cache || has_directive(&cache_directives(self), NO_CACHE)
+    }
+
+    fn no_store(&self) -> bool {
+        let directives = cache_directives(self);
+        has_directive(&directives, NO_STORE) || has_directive(&directives, PRIVATE)
+    }
+
+    fn stale_while_revalidate(&self) -> Option<u64> {
+        directive_seconds(&cache_directives(self), STALE_WHILE_REVALIDATE)
+    }
+
+    fn stale_if_error(&self) -> Option<u64> {
+        directive_seconds(&cache_directives(self), STALE_IF_ERROR)
+    }
+
+    fn vary(&self) -> Option<Vary> {
+        let mut names = self
+            .get_all(VARY)
+            .filter_map(|header_value| header_value.to_str().ok())
+            .flat_map(|s| s.split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<String>>();
+
+        if names.is_empty() {
+            None
+        } else if names.iter().any(|name| name == VARY_ANY) {
+            Some(Vary::Any)
+        } else {
+            names.sort();
+            names.dedup();
+            Some(Vary::Headers(names))
+        }
+    }
+
+    fn etag(&self) -> Option<&str> {
+        self.get_header_value(ETAG.as_str())
+    }
+
+    fn last_modified(&self) -> Option<SystemTime> {
+        http_date(self.get_header_value(LAST_MODIFIED.as_str()))
+    }
+
+    fn has_validators(&self) -> bool {
+        self.contains_key(ETAG) || self.contains_key(LAST_MODIFIED)
+    }
 }
 
-pub fn get_host(req: &HttpRequest) -> String {
-    let conn_info = req.connection_info();
-    let host = conn_info.host();
-    if let Some(idx) = host.find(':') {
-        host[..idx].into()
-    } else {
-        EMPTY.into()
+/// Evaluates client conditional request headers against cached response validators.
+/// If-Modified-Since is only considered when If-None-Match is absent (RFC 7232 section 6).
+pub fn is_not_modified(req_headers: &HeaderMap, res_headers: &HeaderMap) -> bool {
+    if let Some(if_none_match) = req_headers.get_header_value(IF_NONE_MATCH.as_str()) {
+        return match res_headers.etag() {
+            Some(etag) => if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == ETAG_ANY || strip_weak(tag) == strip_weak(etag)),
+            None => false,
+        };
+    }
+
+    let if_modified_since = http_date(req_headers.get_header_value(IF_MODIFIED_SINCE.as_str()));
+    match (if_modified_since, res_headers.last_modified()) {
+        (Some(since), Some(last_modified)) => last_modified <= since,
+        _ => false,
     }
 }
 
+/// Removes headers meaningful only for a single connection, including the ones listed in Connection.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<String>>();
+    for name in listed
+        .iter()
+        .map(String::as_str)
+        .chain(HOP_BY_HOP_HEADERS.iter().copied())
+    {
+        headers.remove(name);
+    }
+}
+
+fn cache_directives(headers: &HeaderMap) -> Vec<(&str, Option<&str>)> {
+    headers
+        .get_all(CACHE_CONTROL)
+        .filter_map(|header_value| header_value.to_str().ok())
+        .flat_map(|s| s.split(','))
+        .map(|directive| {
+            let mut kv_pair = directive.trim().splitn(2, '=');
+            let name = kv_pair.next().unwrap_or(EMPTY);
+            let value = kv_pair.next().map(|v| v.trim_matches('"'));
+            (name, value)
+        })
+        .collect()
+}
+
+fn has_directive(directives: &[(&str, Option<&str>)], directive: &str) -> bool {
+    directives
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case(directive))
+}
+
+fn directive_seconds(directives: &[(&str, Option<&str>)], directive: &str) -> Option<u64> {
+    directives
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(directive))
+        .and_then(|(_, value)| value.and_then(|v| v.parse().ok()))
+}
+
+fn strip_weak(tag: &str) -> &str {
+    tag.trim_start_matches(WEAK_ETAG_PREFIX)
+}
+
+fn http_date(value: Option<&str>) -> Option<SystemTime> {
+    value
+        .and_then(|v| v.parse::<HttpDate>().ok())
+        .map(SystemTime::from)
+}
+
 pub trait Cacheable {
     fn is_cacheable(&self) -> bool;
+
+    // HEAD is answered from cached GET response
+    fn is_served_from_cache(&self) -> bool;
 }
 
 impl Cacheable for HttpRequest {
@@ -81,4 +220,128 @@ impl Cacheable for HttpRequest {
         self.method() == Method::GET
         //todo session data ?
     }
+
+    fn is_served_from_cache(&self) -> bool {
+        self.is_cacheable() || self.method() == Method::HEAD
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{
+        HeaderMap, HeaderName, HeaderValue, CACHE_CONTROL, CONNECTION, CONTENT_TYPE, ETAG,
+        IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, TE, TRANSFER_ENCODING, UPGRADE, VARY,
+    };
+
+    use crate::http_utils::{is_not_modified, remove_hop_by_hop, Headers, Vary};
+
+    #[test]
+    fn should_remove_hop_by_hop_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CONNECTION,
+            HeaderValue::from_static("keep-alive, X-Session"),
+        );
+        headers.append(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(
+            HeaderName::from_static("keep-alive"),
+            HeaderValue::from_static("timeout=5"),
+        );
+        headers.insert(
+            HeaderName::from_static("x-session"),
+            HeaderValue::from_static("abc"),
+        );
+        headers.insert(
+            HeaderName::from_static("proxy-connection"),
+            HeaderValue::from_static("keep-alive"),
+        );
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        headers.insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+
+        remove_hop_by_hop(&mut headers);
+        assert_eq!(1, headers.len());
+        assert!(headers.contains_key(CONTENT_TYPE));
+    }
+
+    #[test]
+    fn should_parse_vary_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            VARY,
+            HeaderValue::from_static("Accept-Language, accept-encoding"),
+        );
+        assert_eq!(
+            Some(Vary::Headers(vec![
+                "accept-encoding".into(),
+                "accept-language".into()
+            ])),
+            (&headers).vary()
+        );
+
+        headers.insert(VARY, HeaderValue::from_static("Accept-Encoding, *"));
+        assert_eq!(Some(Vary::Any), (&headers).vary());
+    }
+
+    #[test]
+    fn should_parse_cache_control_directives() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("public, max-age=60, stale-while-revalidate=30"),
+        );
+        headers.append(
+            CACHE_CONTROL,
+            HeaderValue::from_static("stale-if-error=600"),
+        );
+
+        assert_eq!(Some(60), (&headers).max_age());
+        assert_eq!(Some(30), (&headers).stale_while_revalidate());
+        assert_eq!(Some(600), (&headers).stale_if_error());
+
+        headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_static("Public, Max-Age=120"),
+        );
+        assert_eq!(Some(120), (&headers).max_age());
+    }
+
+    #[test]
+    fn should_match_etag() {
+        let mut res_headers = HeaderMap::new();
+        res_headers.insert(ETAG, HeaderValue::from_static("\"abc\""));
+        let mut req_headers = HeaderMap::new();
+
+        req_headers.insert(
+            IF_NONE_MATCH,
+            HeaderValue::from_static("\"xyz\", W/\"abc\""),
+        );
+        assert!(is_not_modified(&req_headers, &res_headers));
+
+        req_headers.insert(IF_NONE_MATCH, HeaderValue::from_static("\"xyz\""));
+        assert!(!is_not_modified(&req_headers, &res_headers));
+    }
+
+    #[test]
+    fn should_match_last_modified() {
+        let mut res_headers = HeaderMap::new();
+        res_headers.insert(
+            LAST_MODIFIED,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        let mut req_headers = HeaderMap::new();
+
+        req_headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert!(is_not_modified(&req_headers, &res_headers));
+
+        req_headers.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Tue, 20 Oct 2015 07:28:00 GMT"),
+        );
+        assert!(!is_not_modified(&req_headers, &res_headers));
+    }
 }
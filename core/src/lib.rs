@@ -1,15 +1,40 @@
+mod access_log;
 mod balancer;
+mod cache_status;
+mod compression;
 mod config;
+mod file_body;
 mod file_watcher;
+mod forwarded;
+mod header_rules;
 mod http_utils;
 mod log;
 mod matcher;
+mod metrics;
 mod proxy;
-mod yaml_utils;
+mod proxy_protocol;
+mod range;
+mod request_id;
+mod single_flight;
 mod task;
+mod trace;
+mod warm_up;
+mod yaml_utils;
 
+pub use self::access_log::AccessLogger;
 pub use self::balancer::Balancer;
-pub use self::config::Configuration;
+pub use self::cache_status::{CacheOutcome, CacheStatus};
+pub use self::config::{
+    AccessLog, AccessLogField, AccessLogFormat, CacheCompression, CacheStore, Configuration,
+    Encoding, ForwardedHeaders, HeaderActions, HeaderRules, Logging, ResponseCompression, Rotation,
+    Tracing,
+};
 pub use self::file_watcher::FileWatcher;
-pub use self::log::init_logger;
-pub use self::proxy::Proxy;
+pub use self::forwarded::TrustedProxies;
+pub use self::log::{init_logger, reopen_log};
+pub use self::metrics::Metrics;
+pub use self::proxy::{Proxy, Route, Upstream};
+pub use self::proxy_protocol::read_trusted_proxy_header;
+pub use self::request_id::RequestId;
+pub use self::trace::Tracer;
+pub use self::warm_up::warm_up;
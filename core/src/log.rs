@@ -1,18 +1,360 @@
-use log::LevelFilter;
-use simplelog::{ConfigBuilder, TermLogger, TerminalMode, ThreadLogMode, WriteLogger};
-use std::fs::File;
-
-pub fn init_logger(log_path: Option<String>, dev_mode: bool) {
-    let cfg = ConfigBuilder::new()
-        .set_thread_mode(ThreadLogMode::Both)
-        .build();
-
-    if log_path.is_none() || dev_mode {
-        TermLogger::init(LevelFilter::Info, cfg, TerminalMode::Mixed)
-            .expect("Failed to init term logger");
-    } else {
-        let log_file =
-            File::create(format!("{}/roxy.log", log_path.unwrap())).expect("Can't create log file");
-        WriteLogger::init(LevelFilter::Info, cfg, log_file).expect("Failed to init file logger")
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+use crossbeam::sync::ShardedLock;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use time::{Date, OffsetDateTime, PrimitiveDateTime};
+
+use crate::config::{Logging, Rotation};
+use crate::request_id::current_request_id;
+
+const LOG_FILE: &str = "roxy.log";
+const TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+const ROTATED_TIME_FORMAT: &str = "%Y-%m-%dT%H-%M-%S";
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Logs to terminal in dev mode or when log path isn't set, otherwise appends to roxy.log in log path.
+pub fn init_logger(
+    log_path: Option<String>,
+    dev_mode: bool,
+    logging: &Logging,
+) -> anyhow::Result<()> {
+    let file = match log_path {
+        Some(log_path) if !dev_mode => {
+            Some(LogFile::open(Path::new(&log_path).join(LOG_FILE), logging)?)
+        }
+        _ => None,
+    };
+    let logger = LOGGER.get_or_init(|| Logger {
+        levels: ShardedLock::new(Levels::new(logging)),
+        file: Mutex::new(file),
+    });
+    log::set_logger(logger).map_err(|e| anyhow::anyhow!("Failed to init logger. Err = {}", e))?;
+    log::set_max_level(logger.levels().max());
+    Ok(())
+}
+
+/// Reopens log file, e.g. after it was moved by logrotate.
+pub fn reopen_log() -> io::Result<()> {
+    match LOGGER.get() {
+        Some(logger) => match logger.file().as_mut() {
+            Some(file) => file.reopen(),
+            None => Ok(()),
+        },
+        None => Ok(()),
+    }
+}
+
+/// Applies levels of reloaded configuration.
+pub(crate) fn update_levels(logging: &Logging) {
+    if let Some(logger) = LOGGER.get() {
+        let levels = Levels::new(logging);
+        log::set_max_level(levels.max());
+        *logger
+            .levels
+            .write()
+            .expect("log levels write lock poisoned!") = levels;
+    }
+}
+
+struct Logger {
+    levels: ShardedLock<Levels>,
+    // terminal is used when not set
+    file: Mutex<Option<LogFile>>,
+}
+
+impl Logger {
+    fn levels(&self) -> impl std::ops::Deref<Target = Levels> + '_ {
+        self.levels.read().expect("log levels read lock poisoned!")
+    }
+
+    fn file(&self) -> MutexGuard<'_, Option<LogFile>> {
+        self.file.lock().expect("log file lock poisoned!")
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.levels().level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_line(record);
+        match self.file().as_mut() {
+            Some(file) => {
+                if let Err(e) = file.write_line(&line) {
+                    eprintln!("Error writing log file. Err = {}", e);
+                }
+            }
+            None if record.level() <= Level::Warn => {
+                let _ = writeln!(io::stderr().lock(), "{}", line);
+            }
+            None => {
+                let _ = writeln!(io::stdout().lock(), "{}", line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = self.file().as_mut() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+// lines logged while handling a request are prefixed with its id
+fn format_line(record: &Record) -> String {
+    let thread = thread::current();
+    let request_id = current_request_id()
+        .map(|id| format!("[{}] ", id))
+        .unwrap_or_default();
+    format!(
+        "{} [{}] ({}) {}: {}{}",
+        OffsetDateTime::now_utc().format(TIME_FORMAT),
+        record.level(),
+        thread.name().unwrap_or("unnamed"),
+        record.target(),
+        request_id,
+        record.args()
+    )
+}
+
+struct Levels {
+    default: LevelFilter,
+    // longest module path first
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Levels {
+    fn new(logging: &Logging) -> Self {
+        let mut modules = logging
+            .modules
+            .iter()
+            .map(|(module, level)| (module.clone(), *level))
+            .collect::<Vec<(String, LevelFilter)>>();
+        modules.sort_by_key(|(module, _)| std::cmp::Reverse(module.len()));
+        Levels {
+            default: logging.level,
+            modules,
+        }
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .find(|(module, _)| {
+                target
+                    .strip_prefix(module.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+/// Log file rotated by size and time, rotated files are renamed with rotation time suffix.
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    rotation: Rotation,
+    // hour or day of the first line in current file
+    period: (Date, u8),
+    retention: usize,
+}
+
+impl LogFile {
+    fn open(path: PathBuf, logging: &Logging) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        let opened = metadata
+            .modified()
+            .map(OffsetDateTime::from)
+            .unwrap_or_else(|_| OffsetDateTime::now_utc());
+        Ok(LogFile {
+            path,
+            file,
+            size: metadata.len(),
+            max_size: logging.max_size_mb.map(|mb| mb * 1024 * 1024),
+            rotation: logging.rotation,
+            period: period(logging.rotation, opened),
+            retention: logging.retention,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let now = OffsetDateTime::now_utc();
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + line.len() as u64 >= max);
+        if too_big || period(self.rotation, now) != self.period {
+            self.rotate(now)?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotate(&mut self, now: OffsetDateTime) -> io::Result<()> {
+        let time = now.format(ROTATED_TIME_FORMAT);
+        // files rotated within the same second get increasing index
+        let index = self
+            .rotated()?
+            .iter()
+            .filter(|(t, _, _)| *t == time)
+            .map(|(_, index, _)| index + 1)
+            .max();
+        let rotated = match index {
+            Some(index) => format!("{}.{}.{}", self.path.display(), time, index),
+            None => format!("{}.{}", self.path.display(), time),
+        };
+        fs::rename(&self.path, rotated)?;
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        self.period = period(self.rotation, now);
+        self.remove_expired()
+    }
+
+    fn remove_expired(&self) -> io::Result<()> {
+        let rotated = self.rotated()?;
+        let expired = rotated.len().saturating_sub(self.retention);
+        for (_, _, path) in &rotated[..expired] {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    // files rotated by roxy as (time, index, path), oldest first, others (e.g. by logrotate) are left alone
+    fn rotated(&self) -> io::Result<Vec<(String, usize, PathBuf)>> {
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let prefix = format!(
+            "{}.",
+            self.path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default()
+        );
+        let mut rotated = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                let suffix = name.strip_prefix(&prefix)?;
+                let (time, index) = match suffix.split_once('.') {
+                    Some((time, index)) => (time, index.parse().ok()?),
+                    None => (suffix, 0),
+                };
+                PrimitiveDateTime::parse(time, ROTATED_TIME_FORMAT).ok()?;
+                Some((time.to_string(), index, entry.path()))
+            })
+            .collect::<Vec<(String, usize, PathBuf)>>();
+        rotated.sort();
+        Ok(rotated)
+    }
+}
+
+pub(crate) fn open_append<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn period(rotation: Rotation, time: OffsetDateTime) -> (Date, u8) {
+    match rotation {
+        // same period forever
+        Rotation::Never => (Date::try_from_ymd(1970, 1, 1).expect("valid date"), 0),
+        Rotation::Hourly => (time.date(), time.hour()),
+        Rotation::Daily => (time.date(), 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use log::LevelFilter;
+
+    use crate::config::Logging;
+    use crate::log::{Levels, LogFile};
+
+    #[test]
+    fn should_resolve_module_levels() {
+        let mut modules = BTreeMap::new();
+        modules.insert("core".to_string(), LevelFilter::Warn);
+        modules.insert("core::proxy".to_string(), LevelFilter::Debug);
+        let levels = Levels::new(&Logging {
+            modules,
+            ..Logging::default()
+        });
+
+        assert_eq!(LevelFilter::Debug, levels.level("core::proxy"));
+        assert_eq!(LevelFilter::Debug, levels.level("core::proxy::inner"));
+        assert_eq!(LevelFilter::Warn, levels.level("core::config"));
+        assert_eq!(LevelFilter::Info, levels.level("core_ext"));
+        assert_eq!(LevelFilter::Info, levels.level("actix_web::middleware"));
+        assert_eq!(LevelFilter::Debug, levels.max());
+    }
+
+    #[test]
+    fn should_rotate_by_size_and_keep_retained_files() {
+        let dir = std::env::temp_dir().join(format!("roxy-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("roxy.log");
+        fs::write(&path, "previous run\n").unwrap();
+        // rotated by logrotate
+        fs::write(dir.join("roxy.log.1"), "").unwrap();
+        fs::write(dir.join("roxy.log.2.gz"), "").unwrap();
+
+        let mut file = LogFile::open(
+            path.clone(),
+            &Logging {
+                retention: 2,
+                ..Logging::default()
+            },
+        )
+        .unwrap();
+        // appended to the existing file
+        assert_eq!(13, file.size);
+        file.max_size = Some(20);
+        for line in &["first line", "second line", "third line", "fourth line"] {
+            file.write_line(line).unwrap();
+        }
+
+        let mut rotated = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .filter(|name| !["roxy.log", "roxy.log.1", "roxy.log.2.gz"].contains(&name.as_str()))
+            .collect::<Vec<String>>();
+        rotated.sort();
+        assert_eq!(2, rotated.len());
+        assert_eq!(
+            "third line\n",
+            fs::read_to_string(dir.join(&rotated[1])).unwrap()
+        );
+        assert_eq!("fourth line\n", fs::read_to_string(&path).unwrap());
+        assert!(dir.join("roxy.log.1").exists());
+        assert!(dir.join("roxy.log.2.gz").exists());
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -8,7 +8,7 @@ use log::error;
 use regex::Regex;
 use url::Url;
 
-use crate::config::{Group, Outbound, ProxyProperties};
+use crate::config::{Group, Inbound, Outbound, ProxyProperties};
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
 
@@ -58,16 +58,15 @@ impl PathMatcher {
         Ok(props
             .inbound
             .iter()
-            .filter_map(|i| {
-                Self::create_matcher(i.path.as_str(), lookup.get(i.group.as_str())).ok()
-            })
+            .filter_map(|i| Self::create_matcher(i, lookup.get(i.group.as_str())).ok())
             .collect())
     }
 
-    fn create_matcher(path: &str, outbound: Option<&&Outbound>) -> Result<Matcher> {
+    fn create_matcher(inbound: &Inbound, outbound: Option<&&Outbound>) -> Result<Matcher> {
+        let path = inbound.path.as_str();
         if let Some(out) = outbound {
             let regex = Regex::new(path)?;
-            if let Some(group) = Self::convert_to_group(path, out) {
+            if let Some(group) = Self::convert_to_group(inbound, out) {
                 Ok(Matcher { regex, group })
             } else {
                 bail!("Matching group for request path {path} not found")
@@ -77,7 +76,8 @@ impl PathMatcher {
         }
     }
 
-    fn convert_to_group(group: &str, outbound: &Outbound) -> Option<Group> {
+    fn convert_to_group(inbound: &Inbound, outbound: &Outbound) -> Option<Group> {
+        let path = inbound.path.as_str();
         let servers = outbound
             .servers
             .iter()
@@ -85,7 +85,7 @@ impl PathMatcher {
                 if let Ok(url) = Url::parse(v) {
                     Some(url)
                 } else {
-                    error!("Error parsing configuration url {} for group {}", v, group);
+                    error!("Error parsing configuration url {} for group {}", v, path);
                     None
                 }
             })
@@ -99,8 +99,12 @@ impl PathMatcher {
                 .map_or(DEFAULT_TIMEOUT, Duration::from_secs);
             Some(Group {
                 servers,
-                name: group.into(),
+                name: path.into(),
                 timeout,
+                cache_policy: inbound.cache.merge(&outbound.cache),
+                compress: inbound.compress.unwrap_or(true),
+                outbound: outbound.group.clone(),
+                headers: vec![outbound.headers.clone(), inbound.headers.clone()].into(),
             })
         }
     }
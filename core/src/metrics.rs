@@ -0,0 +1,225 @@
+use std::time::Duration;
+
+use actix_web::client::{ConnectError, SendRequestError};
+use anyhow::Result;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+use cache::CacheStorage;
+
+use crate::cache_status::CacheStatus;
+
+/// Proxy metrics exposed in Prometheus text format, shared by proxy and configuration.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    in_flight: IntGauge,
+    upstream_errors: IntCounterVec,
+    // roxy has no active health checks, state is the outcome of last request to the server
+    upstream_up: IntGaugeVec,
+    cache_requests: IntCounterVec,
+    cache_entries: IntGauge,
+    cache_size: IntGauge,
+    config_reloads: IntCounterVec,
+}
+
+/// Counts request as in-flight until dropped.
+pub(crate) struct InFlight<'a>(&'a IntGauge);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry =
+            Registry::new_custom(Some("roxy".to_string()), None).expect("valid metrics prefix");
+        let request_labels = &["route", "group", "upstream", "status"];
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("requests_total", "Proxied requests"),
+                request_labels,
+            )
+            .expect("valid metric"),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("request_duration_seconds", "Proxied request latency"),
+                request_labels,
+            )
+            .expect("valid metric"),
+            in_flight: IntGauge::new("requests_in_flight", "Requests being proxied")
+                .expect("valid metric"),
+            upstream_errors: IntCounterVec::new(
+                Opts::new("upstream_errors_total", "Failed upstream requests"),
+                &["group", "upstream", "kind"],
+            )
+            .expect("valid metric"),
+            upstream_up: IntGaugeVec::new(
+                Opts::new(
+                    "upstream_up",
+                    "Whether upstream server answered its last request",
+                ),
+                &["group", "upstream"],
+            )
+            .expect("valid metric"),
+            cache_requests: IntCounterVec::new(
+                Opts::new("cache_requests_total", "Proxied requests by cache status"),
+                &["status"],
+            )
+            .expect("valid metric"),
+            cache_entries: IntGauge::new("cache_entries", "Cached responses")
+                .expect("valid metric"),
+            cache_size: IntGauge::new("cache_size_bytes", "Cached body bytes")
+                .expect("valid metric"),
+            config_reloads: IntCounterVec::new(
+                Opts::new("config_reloads_total", "Proxy configuration reloads"),
+                &["result"],
+            )
+            .expect("valid metric"),
+            registry,
+        };
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.requests.clone()),
+            Box::new(metrics.request_duration.clone()),
+            Box::new(metrics.in_flight.clone()),
+            Box::new(metrics.upstream_errors.clone()),
+            Box::new(metrics.upstream_up.clone()),
+            Box::new(metrics.cache_requests.clone()),
+            Box::new(metrics.cache_entries.clone()),
+            Box::new(metrics.cache_size.clone()),
+            Box::new(metrics.config_reloads.clone()),
+        ];
+        for collector in collectors {
+            metrics
+                .registry
+                .register(collector)
+                .expect("metric registered once");
+        }
+        metrics
+    }
+
+    pub(crate) fn in_flight(&self) -> InFlight<'_> {
+        self.in_flight.inc();
+        InFlight(&self.in_flight)
+    }
+
+    pub(crate) fn observe_request(
+        &self,
+        route: &str,
+        group: &str,
+        upstream: &str,
+        status: u16,
+        elapsed: Duration,
+    ) {
+        let status = status.to_string();
+        let labels = &[route, group, upstream, status.as_str()];
+        self.requests.with_label_values(labels).inc();
+        self.request_duration
+            .with_label_values(labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn upstream_error(&self, group: &str, upstream: &str, kind: &str) {
+        self.upstream_errors
+            .with_label_values(&[group, upstream, kind])
+            .inc();
+    }
+
+    pub(crate) fn upstream_state(&self, group: &str, upstream: &str, up: bool) {
+        self.upstream_up
+            .with_label_values(&[group, upstream])
+            .set(up as i64);
+    }
+
+    pub(crate) fn cache_request(&self, status: CacheStatus) {
+        self.cache_requests
+            .with_label_values(&[status.as_str()])
+            .inc();
+    }
+
+    pub(crate) fn config_reload(&self, success: bool) {
+        let result = if success { "success" } else { "failure" };
+        self.config_reloads.with_label_values(&[result]).inc();
+    }
+
+    /// Renders metrics in Prometheus text format, cache size is taken from the storage.
+    pub fn render(&self, cache: &dyn CacheStorage) -> Result<String> {
+        let stats = cache.stats();
+        self.cache_entries.set(stats.entries as i64);
+        self.cache_size.set(stats.size as i64);
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// Upstream error kind label of failed upstream request.
+pub(crate) fn error_kind(e: &SendRequestError) -> &'static str {
+    match e {
+        SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) => "timeout",
+        SendRequestError::Connect(_) => "connect",
+        SendRequestError::Send(_) | SendRequestError::Body(_) => "send",
+        SendRequestError::Response(_) | SendRequestError::Http(_) | SendRequestError::H2(_) => {
+            "response"
+        }
+        _ => "request",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use cache::ResponseCache;
+
+    use crate::cache_status::CacheStatus;
+    use crate::metrics::Metrics;
+
+    #[test]
+    fn should_render_prometheus_metrics() {
+        let metrics = Metrics::new();
+        {
+            let _in_flight = metrics.in_flight();
+            metrics.observe_request(
+                "/api/*",
+                "api",
+                "http://backend:8080/",
+                200,
+                Duration::from_millis(20),
+            );
+        }
+        metrics.upstream_error("api", "http://backend:8080/", "timeout");
+        metrics.upstream_state("api", "http://backend:8080/", false);
+        metrics.upstream_state("api", "http://backend:8081/", true);
+        metrics.cache_request(CacheStatus::Hit);
+        metrics.config_reload(false);
+
+        let text = metrics.render(&ResponseCache::with_capacity(10)).unwrap();
+        assert!(text.contains(
+            r#"roxy_requests_total{group="api",route="/api/*",status="200",upstream="http://backend:8080/"} 1"#
+        ));
+        assert!(text.contains(
+            r#"roxy_request_duration_seconds_bucket{group="api",route="/api/*",status="200",upstream="http://backend:8080/",le="0.025"} 1"#
+        ));
+        assert!(text.contains("roxy_requests_in_flight 0"));
+        assert!(text.contains(
+            r#"roxy_upstream_errors_total{group="api",kind="timeout",upstream="http://backend:8080/"} 1"#
+        ));
+        assert!(text.contains(r#"roxy_upstream_up{group="api",upstream="http://backend:8080/"} 0"#));
+        assert!(text.contains(r#"roxy_upstream_up{group="api",upstream="http://backend:8081/"} 1"#));
+        assert!(text.contains(r#"roxy_cache_requests_total{status="HIT"} 1"#));
+        assert!(text.contains("roxy_cache_entries 0"));
+        assert!(text.contains(r#"roxy_config_reloads_total{result="failure"} 1"#));
+    }
+}
@@ -1,157 +1,1042 @@
+use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
 use actix_web::client::{Client, ClientRequest};
-use actix_web::dev::{HttpResponseBuilder, RequestHead};
-use actix_web::http::{StatusCode, Uri};
-use actix_web::web::Bytes;
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::header::{
+    ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, DATE, ETAG, EXPIRES,
+    IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, RANGE, SET_COOKIE, VARY,
+};
+use actix_web::http::{HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use actix_web::rt::time::timeout;
+use actix_web::web::{self, Bytes};
 use actix_web::{HttpRequest, HttpResponse};
 use anyhow::anyhow;
 use anyhow::Result;
-use log::debug;
-use url::Url;
+use log::{debug, error, warn};
+use url::{form_urlencoded, Url};
 
-use cache::{CachedResponse, ResponseCache};
+use cache::{CacheStorage, CachedBody, CachedResponse, DiskCache, ResponseCache, Sweeper};
 
 use crate::balancer::{Balancer, Instance};
-use crate::http_utils::{get_host, Cacheable, Headers, XFF_HEADER_NAME};
-use crate::task::spawn;
+use crate::cache_status::{CacheOutcome, CacheStatus};
+use crate::compression::{
+    compress_response, decoding_for, decompress, is_compressible, is_encoded, to_weak,
+    vary_on_encoding,
+};
+use crate::config::{
+    CacheCompression, CachePolicy, CacheStore, Encoding, ForwardedHeaders, Group,
+    ResponseCompression,
+};
+use crate::file_body::{body_stream, file_stream};
+use crate::forwarded::{ClientInfo, TrustedProxies, CLIENT_ADDRESS_HEADERS};
+use crate::header_rules::TemplateContext;
+use crate::http_utils::{is_not_modified, remove_hop_by_hop, Cacheable, Headers, Vary};
+use crate::metrics::{error_kind, Metrics};
+use crate::range::partial_response;
+use crate::request_id::{current_request_id, with_current_request_id};
+use crate::single_flight::{Flight, FlightGuard, SingleFlight};
+use crate::trace::{Span, SpanKind, Tracer, TRACEPARENT};
 
 // const HTTPS_SCHEME: &str = "https";
 
+// how long expired entries with validators are kept around for revalidation
+const STALE_RETENTION: Duration = Duration::from_secs(600);
+const NOT_MODIFIED_HEADERS: [HeaderName; 6] =
+    [CACHE_CONTROL, DATE, ETAG, EXPIRES, LAST_MODIFIED, VARY];
+
+/// Upstream server which produced the response, kept in response extensions.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub url: String,
+    // until upstream response body was received
+    pub latency: Duration,
+}
+
+impl Upstream {
+    pub fn set(&self, res: &mut HttpResponse) {
+        res.extensions_mut().insert(self.clone());
+    }
+
+    pub fn get<B>(res: &HttpResponse<B>) -> Option<Upstream> {
+        res.extensions().get::<Upstream>().cloned()
+    }
+}
+
+/// Inbound path and outbound group the request was matched to, kept in response extensions.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub path: String,
+    pub group: String,
+}
+
+impl Route {
+    pub fn set(&self, res: &mut HttpResponse) {
+        res.extensions_mut().insert(self.clone());
+    }
+
+    pub fn get<B>(res: &HttpResponse<B>) -> Option<Route> {
+        res.extensions().get::<Route>().cloned()
+    }
+}
+
 pub struct Proxy {
     balancer: Balancer,
-    res_cache: Arc<ResponseCache>,
+    res_cache: Arc<dyn CacheStorage>,
+    revalidating: Mutex<HashSet<Arc<str>>>,
+    in_flight: SingleFlight<SharedResponse>,
+    // how long identical requests wait for the in-flight one before going upstream
+    coalesce_timeout: Duration,
+    sweeper: Option<Sweeper>,
+    snapshot: Option<(Arc<ResponseCache>, PathBuf)>,
+    status_headers: bool,
+    compression: Option<CacheCompression>,
+    response_compression: Option<ResponseCompression>,
+    forwarding: ForwardedHeaders,
+    trusted_proxies: TrustedProxies,
+    tracer: Option<Arc<Tracer>>,
+    metrics: Arc<Metrics>,
+}
+
+// upstream response of the leading request, handed to identical requests waiting for it
+#[derive(Clone)]
+struct SharedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    // variant key of the leading request
+    variant: Arc<str>,
+    upstream: Upstream,
+}
+
+// key can be revalidated again once revalidation completes, panics or is cancelled
+struct Revalidation {
+    proxy: Arc<Proxy>,
+    key: Arc<str>,
+}
+
+impl Drop for Revalidation {
+    fn drop(&mut self) {
+        self.proxy.revalidating_lock().remove(&self.key);
+    }
 }
 
 trait ProxyHeaders {
-    fn append_proxy_headers(self, req_from: &HttpRequest) -> Self;
+    fn append_proxy_headers(
+        self,
+        req_from: &HttpRequest,
+        client: &ClientInfo,
+        config: &ForwardedHeaders,
+    ) -> Self;
 
     fn clear_headers(self) -> Self;
+
+    fn apply_header_rules(self, group: &Group, ctx: &TemplateContext) -> Self;
+
+    fn set_validators(self, cached: Option<&CachedResponse>) -> Self;
+
+    fn remove_range(self, remove: bool) -> Self;
+
+    fn propagate_trace(self, span: Option<&Span>) -> Self;
 }
 
 impl ProxyHeaders for ClientRequest {
-    fn append_proxy_headers(self, req_from: &HttpRequest) -> Self {
-        let headers = req_from.headers();
-        let xff = headers.xff();
-        let mut xff_value = String::new();
-        if let Some(xff) = xff {
-            xff_value.push_str(xff);
-            xff_value.push_str(", ");
+    fn append_proxy_headers(
+        mut self,
+        req_from: &HttpRequest,
+        client: &ClientInfo,
+        config: &ForwardedHeaders,
+    ) -> Self {
+        if !client.trusted {
+            for name in CLIENT_ADDRESS_HEADERS.iter() {
+                self.headers_mut().remove(*name);
+            }
         }
-        xff_value.push_str(get_host(req_from).as_str());
-
-        self.set_header(XFF_HEADER_NAME, xff_value)
+        for (name, value) in client.headers(config, req_from) {
+            match HeaderValue::from_str(&value) {
+                Ok(value) => {
+                    self.headers_mut().insert(name, value);
+                }
+                Err(e) => warn!("Invalid {} header value {}. Err = {}", name, value, e),
+            }
+        }
+        self
     }
 
     fn clear_headers(mut self) -> Self {
-        self.headers_mut().remove("Connection");
+        remove_hop_by_hop(self.headers_mut());
+        self
+    }
+
+    fn apply_header_rules(mut self, group: &Group, ctx: &TemplateContext) -> Self {
+        for rules in group.headers.iter() {
+            rules.request.apply(self.headers_mut(), ctx);
+        }
+        self
+    }
+
+    fn set_validators(mut self, cached: Option<&CachedResponse>) -> Self {
+        if let Some(cached) = cached.filter(|c| (&c.headers).has_validators()) {
+            let headers = self.headers_mut();
+            headers.remove(IF_NONE_MATCH);
+            headers.remove(IF_MODIFIED_SINCE);
+            if let Some(etag) = cached.headers.get(ETAG) {
+                headers.insert(IF_NONE_MATCH, etag.clone());
+            }
+            if let Some(last_modified) = cached.headers.get(LAST_MODIFIED) {
+                headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+            }
+        }
+        self
+    }
+
+    // complete body is fetched so it can be cached, requested ranges are served from it
+    fn remove_range(mut self, remove: bool) -> Self {
+        if remove {
+            self.headers_mut().remove(RANGE);
+            self.headers_mut().remove(IF_RANGE);
+        }
+        self
+    }
+
+    // tracestate is relayed as received
+    fn propagate_trace(mut self, span: Option<&Span>) -> Self {
+        if let Some(span) = span {
+            match HeaderValue::from_str(&span.traceparent()) {
+                Ok(value) => {
+                    self.headers_mut()
+                        .insert(HeaderName::from_static(TRACEPARENT), value);
+                }
+                Err(e) => warn!("Invalid traceparent {}. Err = {}", span.traceparent(), e),
+            }
+        }
         self
     }
 }
 
 impl Proxy {
-    pub fn new(balancer: Balancer) -> Result<Self> {
-        let res_cache = Arc::new(ResponseCache::with_capacity(10_000));
-        let proxy = Proxy {
+    pub fn new(balancer: Balancer, store: CacheStore) -> Result<Self> {
+        // cached bodies are decoded for clients not accepting the encoding, zstd has no decoder
+        if let Some(Encoding::Zstd) = store.compression.as_ref().map(|c| c.encoding) {
+            return Err(anyhow!("zstd is not supported for cache compression"));
+        }
+        let res_cache = match store.shards {
+            Some(shards) => ResponseCache::with_shards(store.capacity, shards),
+            None => ResponseCache::with_capacity(store.capacity),
+        };
+        let res_cache = match store.disk {
+            Some(disk) => {
+                let disk_cache = DiskCache::open(disk.path, disk.max_size_mb * 1024 * 1024)?;
+                res_cache.with_disk(disk_cache)
+            }
+            None => res_cache,
+        };
+        let res_cache = Arc::new(res_cache);
+        let snapshot = store.snapshot.map(PathBuf::from);
+        if let Some(path) = snapshot.as_ref().filter(|path| path.exists()) {
+            if let Err(e) = res_cache.load_snapshot(path) {
+                warn!("Error loading cache snapshot {:?}. Err = {}", path, e);
+            }
+        }
+        let sweeper = Sweeper::start(res_cache.clone(), Duration::from_secs(store.sweep_interval))?;
+
+        Ok(Proxy {
+            sweeper: Some(sweeper),
+            snapshot: snapshot.map(|path| (res_cache.clone(), path)),
+            status_headers: store.status_headers,
+            compression: store.compression,
+            coalesce_timeout: Duration::from_secs(store.coalesce_timeout),
+            ..Self::with_cache(balancer, res_cache)
+        })
+    }
+
+    /// Creates proxy on top of provided cache storage, storage is responsible for its own expiry.
+    pub fn with_cache(balancer: Balancer, res_cache: Arc<dyn CacheStorage>) -> Self {
+        Proxy {
             balancer,
             res_cache,
-        };
-        proxy.run_expire();
-        Ok(proxy)
+            revalidating: Mutex::new(HashSet::new()),
+            in_flight: SingleFlight::new(),
+            coalesce_timeout: Duration::from_secs(CacheStore::default().coalesce_timeout),
+            sweeper: None,
+            snapshot: None,
+            status_headers: false,
+            compression: None,
+            response_compression: None,
+            forwarding: ForwardedHeaders::default(),
+            trusted_proxies: TrustedProxies::default(),
+            tracer: None,
+            metrics: Arc::new(Metrics::new()),
+        }
     }
 
-    pub async fn proxy(&self, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
-        let key = Self::build_cache_key(&req);
-        let req_cacheable = req.is_cacheable();
-        if req_cacheable {
-            if let Some(res) = self.cache_read(key.clone()) {
-                return Ok(res);
+    /// Records proxied requests in given metrics instead of proxy's own.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Compresses cacheable bodies before they are stored.
+    pub fn with_cache_compression(mut self, compression: Option<CacheCompression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compresses proxied responses on the fly for groups with compression enabled.
+    pub fn with_response_compression(mut self, compression: Option<ResponseCompression>) -> Self {
+        self.response_compression = compression;
+        self
+    }
+
+    /// Selects forwarding headers added to upstream requests.
+    pub fn with_forwarding(mut self, forwarding: ForwardedHeaders) -> Self {
+        self.forwarding = forwarding;
+        self
+    }
+
+    /// Proxies in front of roxy whose forwarding headers are kept, they are stripped from other peers.
+    pub fn with_trusted_proxies(mut self, trusted_proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    /// Traces proxied requests, incoming traceparent is relayed unchanged when not set.
+    pub fn with_tracer(mut self, tracer: Option<Arc<Tracer>>) -> Self {
+        self.tracer = tracer;
+        self
+    }
+
+    /// How long identical cacheable requests wait for the in-flight one before going upstream.
+    pub fn with_coalesce_timeout(mut self, timeout: Duration) -> Self {
+        self.coalesce_timeout = timeout;
+        self
+    }
+
+    /// Adds X-Cache and Cache-Status headers to proxied responses.
+    pub fn with_status_headers(mut self, enabled: bool) -> Self {
+        self.status_headers = enabled;
+        self
+    }
+
+    /// Stops background cache maintenance and saves cache snapshot if configured.
+    pub fn shutdown(&self) {
+        if let Some(sweeper) = &self.sweeper {
+            sweeper.stop();
+        }
+        if let Some((cache, path)) = &self.snapshot {
+            if let Err(e) = cache.save_snapshot(path) {
+                error!("Error saving cache snapshot {:?}. Err = {}", path, e);
+            }
+        }
+    }
+
+    pub fn cache(&self) -> Arc<dyn CacheStorage> {
+        self.res_cache.clone()
+    }
+
+    pub async fn proxy(self: Arc<Self>, req: HttpRequest, body: Bytes) -> Result<HttpResponse> {
+        let _in_flight = self.metrics.in_flight();
+        let started = Instant::now();
+        let mut span = self
+            .tracer
+            .as_ref()
+            .map(|tracer| tracer.start_request(&req));
+        let res = match self.route(&req).await {
+            Ok(group) => {
+                let res = self.clone().handle(&req, &group, body).await;
+                self.observe_request(Some(&group), &res, started);
+                res
+            }
+            Err(e) => {
+                let res = Err(e);
+                self.observe_request(None, &res, started);
+                res
+            }
+        };
+        if let Some(span) = span.as_mut() {
+            match &res {
+                Ok(res) => span.set_attribute("http.status_code", res.status().as_u16()),
+                Err(e) => span.set_error(e),
             }
         }
+        res
+    }
 
-        let instance = self.balancer.balance(&req).await?;
-        let (mut resp_builder, bytes) = Self::send(instance, req.head(), &req, body).await?;
-        let res = resp_builder.body(bytes.clone());
-        if req_cacheable {
-            self.cache_write(key, &res, bytes);
+    async fn route(&self, req: &HttpRequest) -> Result<Group> {
+        let span = self.span(req, "route", SpanKind::Internal);
+        let group = self.balancer.find_group(req).await?;
+        if let Some(mut span) = span {
+            span.set_attribute("roxy.group", &group.outbound);
         }
+        Ok(group)
+    }
+
+    async fn handle(
+        self: Arc<Self>,
+        req: &HttpRequest,
+        group: &Group,
+        body: Bytes,
+    ) -> Result<HttpResponse> {
+        let mut res = self.clone().serve(req, group, body).await?;
+        if self.status_headers {
+            if let Some(outcome) = CacheOutcome::get(&res) {
+                outcome.write_headers(&mut res);
+            }
+        }
+        // hop-by-hop headers are never relayed, actix sets its own connection headers
+        remove_hop_by_hop(res.headers_mut());
+        self.apply_response_rules(req, group, &mut res);
+        if let Some(compression) = self
+            .response_compression
+            .as_ref()
+            .filter(|_| group.compress)
+        {
+            res = compress_response(compression, req, res);
+        }
+        Route {
+            path: group.name.clone(),
+            group: group.outbound.clone(),
+        }
+        .set(&mut res);
         Ok(res)
     }
 
-    fn cache_read(&self, key: Arc<str>) -> Option<HttpResponse> {
-        if let Some(res) = self.res_cache.get(key) {
-            if res.expired() {
-                None
-            } else {
-                let mut response = HttpResponse::build(res.status_code).body(res.body);
-                *response.headers_mut() = res.headers;
-                Some(response)
+    // failed requests are answered with 500 by the server, unrouted ones have no route and group
+    fn observe_request(&self, group: Option<&Group>, res: &Result<HttpResponse>, started: Instant) {
+        let (upstream, status) = match res {
+            Ok(res) => {
+                if let Some(outcome) = CacheOutcome::get(res) {
+                    self.metrics.cache_request(outcome.status);
+                }
+                (Upstream::get(res), res.status())
             }
+            Err(_) => (None, StatusCode::INTERNAL_SERVER_ERROR),
+        };
+        self.metrics.observe_request(
+            group.map_or("", |group| group.name.as_str()),
+            group.map_or("", |group| group.outbound.as_str()),
+            upstream
+                .as_ref()
+                .map_or("", |upstream| upstream.url.as_str()),
+            status.as_u16(),
+            started.elapsed(),
+        );
+    }
+
+    fn apply_response_rules(&self, req: &HttpRequest, group: &Group, res: &mut HttpResponse) {
+        if group.headers.iter().all(|rules| rules.response.is_empty()) {
+            return;
+        }
+        let client = ClientInfo::from_request(req, &self.trusted_proxies);
+        let upstream = Upstream::get(res).map(|upstream| upstream.url);
+        let request_id = current_request_id();
+        let ctx = TemplateContext {
+            client_ip: client.client_ip,
+            request_id: request_id.as_deref(),
+            group: &group.outbound,
+            upstream: upstream.as_deref(),
+        };
+        for rules in group.headers.iter() {
+            rules.response.apply(res.headers_mut(), &ctx);
+        }
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        req: &HttpRequest,
+        group: &Group,
+        body: Bytes,
+    ) -> Result<HttpResponse> {
+        let key = Self::build_cache_key(req, &group.cache_policy);
+        let cacheable = Self::cacheable(req, group);
+        let mut stale = None;
+        if Self::served_from_cache(req, group) {
+            let cached = {
+                let mut span = self.span(req, "cache lookup", SpanKind::Internal);
+                let cached = self.cache_lookup(key.clone(), req.headers()).await;
+                self.res_cache.count_lookup(cached.is_some());
+                if let Some(span) = span.as_mut() {
+                    span.set_attribute("roxy.cache.hit", cached.is_some());
+                }
+                cached
+            };
+            if let Some(cached) = cached {
+                if !cached.expired() {
+                    return Ok(Self::cached_response(cached, req, CacheStatus::Hit));
+                }
+                let swr = group
+                    .cache_policy
+                    .stale_while_revalidate
+                    .or_else(|| (&cached.headers).stale_while_revalidate());
+                if Self::stale_within(&cached, swr) {
+                    if cacheable {
+                        self.clone().revalidate_in_background(
+                            key,
+                            req.clone(),
+                            group.clone(),
+                            cached.clone(),
+                        );
+                    }
+                    return Ok(Self::cached_response(cached, req, CacheStatus::Stale));
+                }
+                stale = Some(cached);
+            }
+        }
+
+        let res = if cacheable {
+            self.fetch_coalesced(key, req, group, body, stale.as_ref())
+                .await
         } else {
-            None
+            self.fetch(key, req, group, body, stale.as_ref(), None)
+                .await
+        };
+        match stale {
+            Some(cached) if Self::upstream_failed(&res) => {
+                let sie = group
+                    .cache_policy
+                    .stale_if_error
+                    .or_else(|| (&cached.headers).stale_if_error());
+                if Self::stale_within(&cached, sie) {
+                    warn!("Upstream failed for {}, serving stale response", req.uri());
+                    Ok(Self::cached_response(cached, req, CacheStatus::Stale))
+                } else {
+                    res
+                }
+            }
+            _ => res,
         }
     }
 
-    fn cache_write(&self, key: Arc<str>, res: &HttpResponse, body: Bytes) {
-        if res.status() != StatusCode::OK {
-            return;
+    // identical cacheable requests wait for the in-flight one and get its response,
+    // they fall back to cache or upstream when it isn't shared with them
+    async fn fetch_coalesced(
+        &self,
+        key: Arc<str>,
+        req: &HttpRequest,
+        group: &Group,
+        body: Bytes,
+        stale: Option<&CachedResponse>,
+    ) -> Result<HttpResponse> {
+        let flight_key = self.flight_key(&key, req.headers()).await;
+        let shared = match self.in_flight.join(flight_key) {
+            Flight::Leader(flight) => {
+                return self.fetch(key, req, group, body, stale, Some(flight)).await
+            }
+            Flight::Follower(shared) => shared,
+        };
+        match timeout(self.coalesce_timeout, shared).await {
+            Ok(Ok(shared)) => {
+                if let Some(res) = Self::shared_response(&key, shared, req) {
+                    return Ok(res);
+                }
+            }
+            Ok(Err(_)) => debug!("In-flight request {} wasn't shared", &key),
+            Err(_) => debug!("Timed out waiting for in-flight request {}", &key),
+        }
+        match self.cache_lookup(key.clone(), req.headers()).await {
+            Some(cached) if !cached.expired() => {
+                Ok(Self::cached_response(cached, req, CacheStatus::Hit))
+            }
+            _ => self.fetch(key, req, group, body, stale, None).await,
+        }
+    }
+
+    // requests are coalesced per variant once upstream Vary is known from the cached entry
+    async fn flight_key(&self, key: &Arc<str>, req_headers: &HeaderMap) -> Arc<str> {
+        match self
+            .cache_get(key.clone())
+            .await
+            .and_then(|res| (&res.headers).vary())
+        {
+            Some(Vary::Headers(names)) => Self::build_variant_key(key, &names, req_headers),
+            _ => key.clone(),
+        }
+    }
+
+    // variant of response selected by request, none when response can't be shared
+    fn shared_variant(
+        key: &Arc<str>,
+        headers: &HeaderMap,
+        req_headers: &HeaderMap,
+    ) -> Option<Arc<str>> {
+        match headers.vary() {
+            None => Some(key.clone()),
+            Some(Vary::Any) => None,
+            Some(Vary::Headers(names)) => Some(Self::build_variant_key(key, &names, req_headers)),
+        }
+    }
+
+    // shared response is used only when request selects the same variant as the leading one
+    fn shared_response(
+        key: &Arc<str>,
+        shared: SharedResponse,
+        req: &HttpRequest,
+    ) -> Option<HttpResponse> {
+        if Self::shared_variant(key, &shared.headers, req.headers())? != shared.variant {
+            return None;
         }
-        let max_age = res.headers().max_age();
-        if max_age.is_none() {
+        let mut res = match partial_response(
+            req,
+            shared.status,
+            &shared.headers,
+            &shared.body.clone().into(),
+        ) {
+            Some(partial) => partial,
+            None => {
+                let mut res = HttpResponse::build(shared.status).body(shared.body);
+                *res.headers_mut() = shared.headers;
+                res
+            }
+        };
+        CacheOutcome::new(CacheStatus::Miss)
+            .collapsed(true)
+            .set(&mut res);
+        shared.upstream.set(&mut res);
+        Some(res)
+    }
+
+    async fn fetch(
+        &self,
+        key: Arc<str>,
+        req: &HttpRequest,
+        group: &Group,
+        body: Bytes,
+        stale: Option<&CachedResponse>,
+        flight: Option<FlightGuard<'_, SharedResponse>>,
+    ) -> Result<HttpResponse> {
+        let instance = self.balancer.balance(group);
+        let url = instance.url.to_string();
+        let cacheable = Self::cacheable(req, group);
+        // range is fetched whole only when the response is expected to be stored,
+        // as a refresh of cached entry or by policy storing responses without cache headers
+        let full_body = cacheable && (stale.is_some() || group.cache_policy.default_ttl.is_some());
+        let started = Instant::now();
+        let (mut resp_builder, bytes) = self
+            .send(instance, req, group, body, stale, full_body)
+            .await?;
+        let upstream = Upstream {
+            url,
+            latency: started.elapsed(),
+        };
+        let mut res = resp_builder.body(bytes.clone());
+        // only responses the cache would store are shared, waiting requests go upstream otherwise
+        if let Some(flight) = flight.filter(|_| Self::storable(&group.cache_policy, &res)) {
+            if let Some(variant) = Self::shared_variant(&key, res.headers(), req.headers()) {
+                let mut headers = res.headers().clone();
+                headers.remove(SET_COOKIE);
+                flight.complete(&SharedResponse {
+                    status: res.status(),
+                    headers,
+                    body: bytes.clone(),
+                    variant,
+                    upstream: upstream.clone(),
+                });
+            }
+        }
+        if let Some(cached) = stale {
+            if res.status() == StatusCode::NOT_MODIFIED {
+                let mut res = self
+                    .cache_refresh(key, req, group, cached.clone(), &res)
+                    .await;
+                upstream.set(&mut res);
+                return Ok(res);
+            }
+        }
+        let outcome = if cacheable {
+            let stored = self.cache_write(key, req, group, &res, bytes.clone()).await;
+            if let Some(partial) = partial_response(req, res.status(), res.headers(), &bytes.into())
+            {
+                res = partial;
+            }
+            CacheOutcome::new(CacheStatus::Miss).stored(stored)
+        } else if Self::served_from_cache(req, group) {
+            CacheOutcome::new(CacheStatus::Miss)
+        } else {
+            CacheOutcome::new(CacheStatus::Bypass)
+        };
+        outcome.set(&mut res);
+        upstream.set(&mut res);
+        Ok(res)
+    }
+
+    fn revalidate_in_background(
+        self: Arc<Self>,
+        key: Arc<str>,
+        req: HttpRequest,
+        group: Group,
+        stale: CachedResponse,
+    ) {
+        if !self.revalidating_lock().insert(key.clone()) {
             return;
         }
+        let revalidation = Revalidation {
+            proxy: self.clone(),
+            key: key.clone(),
+        };
+
+        actix_web::rt::spawn(with_current_request_id(async move {
+            let res = self
+                .fetch(key.clone(), &req, &group, Bytes::new(), Some(&stale), None)
+                .await;
+            if let Err(e) = res {
+                error!("Background revalidation of {} failed. Err = {}", &key, e);
+            }
+            drop(revalidation);
+        }));
+    }
+
+    fn cacheable(req: &HttpRequest, group: &Group) -> bool {
+        req.is_cacheable() && group.cache_policy.enabled()
+    }
+
+    fn served_from_cache(req: &HttpRequest, group: &Group) -> bool {
+        req.is_served_from_cache() && group.cache_policy.enabled()
+    }
+
+    fn upstream_failed(res: &Result<HttpResponse>) -> bool {
+        match res {
+            Ok(res) => res.status().is_server_error(),
+            Err(_) => true,
+        }
+    }
+
+    fn stale_within(cached: &CachedResponse, window: Option<u64>) -> bool {
+        window.is_some_and(|secs| cached.ttl + Duration::from_secs(secs) > SystemTime::now())
+    }
+
+    fn cached_response(
+        res: CachedResponse,
+        req: &HttpRequest,
+        status: CacheStatus,
+    ) -> HttpResponse {
+        let outcome = CacheOutcome::cached(status, res.ttl);
+        let mut response = Self::cached_body_response(res, req);
+        outcome.set(&mut response);
+        response
+    }
+
+    fn cached_body_response(res: CachedResponse, req: &HttpRequest) -> HttpResponse {
+        if is_not_modified(req.headers(), &res.headers) {
+            let mut response = HttpResponse::NotModified().finish();
+            for name in NOT_MODIFIED_HEADERS.iter() {
+                for value in res.headers.get_all(name) {
+                    response.headers_mut().append(name.clone(), value.clone());
+                }
+            }
+            response
+        } else if let Some(encoding) = decoding_for(req.headers(), &res.headers) {
+            let mut response = HttpResponse::build(res.status_code)
+                .streaming(decompress(body_stream(res.body), encoding));
+            *response.headers_mut() = res.headers;
+            let headers = response.headers_mut();
+            headers.remove(CONTENT_ENCODING);
+            headers.remove(CONTENT_LENGTH);
+            vary_on_encoding(headers);
+            response
+        } else if let Some(partial) =
+            partial_response(req, res.status_code, &res.headers, &res.body)
+        {
+            partial
+        } else {
+            let mut builder = HttpResponse::build(res.status_code);
+            let mut response = match res.body {
+                CachedBody::Bytes(bytes) => builder.body(bytes),
+                CachedBody::File(file) => builder.body(file_stream(file)),
+            };
+            *response.headers_mut() = res.headers;
+            if response.status() == StatusCode::OK {
+                response
+                    .headers_mut()
+                    .insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+            }
+            if is_encoded(response.headers()) {
+                vary_on_encoding(response.headers_mut());
+            }
+            response
+        }
+    }
+
+    // entry stored under the primary key carries the upstream Vary header,
+    // the matching variant is stored under a key extended with request header values
+    async fn cache_lookup(&self, key: Arc<str>, req_headers: &HeaderMap) -> Option<CachedResponse> {
+        let res = self.cache_get(key.clone()).await?;
+        match (&res.headers).vary() {
+            None => Some(res),
+            Some(Vary::Any) => None,
+            Some(Vary::Headers(names)) => {
+                self.cache_get(Self::build_variant_key(&key, &names, req_headers))
+                    .await
+            }
+        }
+    }
 
-        let status_code = res.status();
-        let headers = res.headers().clone();
-        let ttl = Instant::now() + Duration::from_secs(max_age.unwrap());
+    // lookups are kept off the worker thread too
+    async fn cache_get(&self, key: Arc<str>) -> Option<CachedResponse> {
+        let res_cache = self.res_cache.clone();
+        web::block(move || Ok::<_, ()>(res_cache.get(&key)))
+            .await
+            .unwrap_or(None)
+    }
 
-        let response = CachedResponse {
-            status_code,
-            body,
-            headers,
-            ttl,
+    async fn cache_write(
+        &self,
+        key: Arc<str>,
+        req: &HttpRequest,
+        group: &Group,
+        res: &HttpResponse,
+        body: Bytes,
+    ) -> bool {
+        if !Self::storable(&group.cache_policy, res) {
+            return false;
+        }
+        let ttl = match Self::cache_ttl(&group.cache_policy, res.headers()) {
+            Some(ttl) => ttl,
+            None => return false,
         };
 
-        self.res_cache.put(key, response, ttl);
+        let mut response = CachedResponse {
+            status_code: res.status(),
+            body: body.into(),
+            headers: res.headers().clone(),
+            ttl: SystemTime::now() + ttl,
+        };
+        if let Some(compression) = &self.compression {
+            response = Self::compress(compression, response).await;
+        }
+        self.cache_store(key, req, &group.cache_policy, response)
+            .await
+    }
+
+    // compressed representation differs from upstream one so its etag becomes weak
+    async fn compress(
+        compression: &CacheCompression,
+        mut response: CachedResponse,
+    ) -> CachedResponse {
+        let headers = &response.headers;
+        if is_encoded(headers)
+            || response.body.len() < compression.min_size
+            || !is_compressible(headers, &compression.content_types)
+        {
+            return response;
+        }
+        let body = match &response.body {
+            CachedBody::Bytes(bytes) => bytes.clone(),
+            CachedBody::File(_) => return response,
+        };
+
+        let encoding = compression.encoding;
+        match web::block(move || encoding.compress(&body)).await {
+            Ok(compressed) => {
+                response.body = Bytes::from(compressed).into();
+                let weak_etag = to_weak(&response.headers);
+                let headers = &mut response.headers;
+                headers.remove(CONTENT_LENGTH);
+                headers.insert(
+                    CONTENT_ENCODING,
+                    HeaderValue::from_static(encoding.as_str()),
+                );
+                if let Some(weak_etag) = weak_etag {
+                    headers.insert(ETAG, weak_etag);
+                }
+            }
+            Err(e) => warn!("Error compressing cached response. Err = {}", e),
+        }
+        response
+    }
+
+    // stored headers are updated with the ones received in 304 (RFC 7234 section 4.3.4)
+    async fn cache_refresh(
+        &self,
+        key: Arc<str>,
+        req: &HttpRequest,
+        group: &Group,
+        mut cached: CachedResponse,
+        not_modified: &HttpResponse,
+    ) -> HttpResponse {
+        let stored_etag = cached.headers.get(ETAG).cloned();
+        for name in not_modified.headers().keys() {
+            cached.headers.remove(name);
+        }
+        for (name, value) in not_modified.headers().iter() {
+            cached.headers.append(name.clone(), value.clone());
+        }
+        // entry stored compressed keeps the weak form of upstream etag
+        if let Some(etag) =
+            to_weak(&cached.headers).filter(|etag| Some(etag) == stored_etag.as_ref())
+        {
+            cached.headers.insert(ETAG, etag);
+        }
+
+        let stored = match Self::cache_ttl(&group.cache_policy, &cached.headers) {
+            Some(ttl) => {
+                cached.ttl = SystemTime::now() + ttl;
+                self.cache_store(key, req, &group.cache_policy, cached.clone())
+                    .await
+            }
+            None => false,
+        };
+        let outcome = CacheOutcome::cached(CacheStatus::Revalidated, cached.ttl).stored(stored);
+        let mut response = Self::cached_body_response(cached, req);
+        outcome.set(&mut response);
+        response
+    }
+
+    fn storable(policy: &CachePolicy, res: &HttpResponse) -> bool {
+        res.status() == StatusCode::OK
+            && res.headers().vary() != Some(Vary::Any)
+            && Self::cache_ttl(policy, res.headers()).is_some()
+    }
+
+    fn cache_ttl(policy: &CachePolicy, headers: &HeaderMap) -> Option<Duration> {
+        if headers.no_store() || (headers.no_cache() && !policy.ignore_no_cache()) {
+            return None;
+        }
+
+        let ttl = if headers.contains_key(CACHE_CONTROL) {
+            // no-cache response has no freshness of its own when it's stored anyway
+            headers
+                .max_age()
+                .or_else(|| policy.default_ttl.filter(|_| policy.ignore_no_cache()))
+        } else {
+            policy.default_ttl
+        };
+        ttl.map(|ttl| policy.max_ttl.map_or(ttl, |max_ttl| ttl.min(max_ttl)))
+            .map(Duration::from_secs)
+    }
+
+    // stale entries are kept as long as they can be revalidated or served stale,
+    // cookies set for the client of the stored response aren't replayed to others
+    async fn cache_store(
+        &self,
+        key: Arc<str>,
+        req: &HttpRequest,
+        policy: &CachePolicy,
+        mut response: CachedResponse,
+    ) -> bool {
+        response.headers.remove(SET_COOKIE);
+        let headers = &response.headers;
+        let revalidate = if headers.has_validators() {
+            STALE_RETENTION
+        } else {
+            Duration::from_secs(0)
+        };
+        let stale_secs = policy
+            .stale_while_revalidate
+            .or_else(|| headers.stale_while_revalidate())
+            .max(policy.stale_if_error.or_else(|| headers.stale_if_error()))
+            .unwrap_or(0);
+        let expire_at = response.ttl + revalidate.max(Duration::from_secs(stale_secs));
+
+        if let Some(Vary::Headers(names)) = (&response.headers).vary() {
+            // primary key only tells which request headers select the variant
+            let mut vary = HeaderMap::new();
+            for value in response.headers.get_all(VARY) {
+                vary.append(VARY, value.clone());
+            }
+            let marker = CachedResponse {
+                status_code: response.status_code,
+                headers: vary,
+                body: Bytes::new().into(),
+                ttl: response.ttl,
+            };
+            let variant_key = Self::build_variant_key(&key, &names, req.headers());
+            return self.cache_put(variant_key, response, expire_at).await
+                && self.cache_put(key, marker, expire_at).await;
+        }
+        self.cache_put(key, response, expire_at).await
+    }
+
+    // storage may write to disk or network, so it's kept off the worker thread
+    async fn cache_put(
+        &self,
+        key: Arc<str>,
+        response: CachedResponse,
+        expire_at: SystemTime,
+    ) -> bool {
+        let res_cache = self.res_cache.clone();
+        web::block(move || Ok::<_, ()>(res_cache.put(key, response, expire_at)))
+            .await
+            .unwrap_or(false)
     }
 
     async fn send(
+        &self,
         instance: Instance,
-        req_head: &RequestHead,
         req: &HttpRequest,
+        group: &Group,
         body: Bytes,
+        stale: Option<&CachedResponse>,
+        full_body: bool,
     ) -> Result<(HttpResponseBuilder, Bytes)> {
+        let upstream = instance.url.to_string();
         let proxy_uri = Self::create_proxy_uri(instance.url, req.path(), req.query_string())?;
 
-        debug!("proxying to {}", &proxy_uri);
-        let mut response = Self::create_http_client(instance.timeout)
-            .request_from(proxy_uri, req_head)
-            .append_proxy_headers(req)
+        let client = ClientInfo::from_request(req, &self.trusted_proxies);
+        let request_id = current_request_id();
+        let mut span = self.span(req, "upstream", SpanKind::Client);
+        if let Some(span) = span.as_mut() {
+            span.set_attribute("http.url", &proxy_uri);
+            span.set_attribute("roxy.upstream", &upstream);
+        }
+        debug!(
+            "proxying to {} for {}",
+            &proxy_uri,
+            client
+                .client_ip
+                .map_or_else(|| "unknown".to_string(), |ip| ip.to_string())
+        );
+        let response = Self::create_http_client(instance.timeout)
+            .request_from(proxy_uri, req.head())
+            // body is relayed as encoded by upstream, together with its Content-Encoding
+            .no_decompress()
             .clear_headers()
+            .append_proxy_headers(req, &client, &self.forwarding)
+            .apply_header_rules(
+                group,
+                &TemplateContext {
+                    client_ip: client.client_ip,
+                    request_id: request_id.as_deref(),
+                    group: &group.outbound,
+                    upstream: Some(&upstream),
+                },
+            )
+            .set_validators(stale)
+            .remove_range(full_body)
+            .propagate_trace(span.as_ref())
             .send_body(body)
-            .await
-            .map_err(|e| anyhow!("http proxy error {:?}", e))?;
+            .await;
+        if let Some(span) = span.as_mut() {
+            match &response {
+                Ok(res) => span.set_attribute("http.status_code", res.status().as_u16()),
+                Err(e) => span.set_error(e),
+            }
+        }
+        self.metrics
+            .upstream_state(&group.outbound, &upstream, response.is_ok());
+        let mut response = response.map_err(|e| {
+            self.metrics
+                .upstream_error(&group.outbound, &upstream, error_kind(&e));
+            anyhow!("http proxy error {:?}", e)
+        })?;
 
-        let resp_builder = HttpResponse::build(response.status());
-        let bytes = response.body().await?;
+        let mut headers = response.headers().clone();
+        remove_hop_by_hop(&mut headers);
+        let mut resp_builder = HttpResponse::build(response.status());
+        for (name, value) in headers.iter() {
+            resp_builder.header(name.clone(), value.clone());
+        }
+        let bytes = response.body().await.inspect_err(|_| {
+            self.metrics
+                .upstream_error(&group.outbound, &upstream, "body");
+        })?;
 
         Ok((resp_builder, bytes))
     }
 
-    fn run_expire(&self) {
-        let cache = self.res_cache.clone();
-        spawn(
-            move || loop {
-                cache.expire_head();
-            },
-            "expire-items-thread".into(),
-        )
-        .expect("Failed to spawn expiring cache thread");
-    }
-
     fn create_proxy_uri(url: Url, path: &str, query_string: &str) -> Result<Uri> {
         let mut url = url;
         url.set_path(format!("{}{}", &url.path()[1..], path).as_str());
@@ -162,12 +1047,73 @@ impl Proxy {
         Ok(Uri::try_from(url.as_str())?)
     }
 
+    fn span(&self, req: &HttpRequest, name: &str, kind: SpanKind) -> Option<Span> {
+        self.tracer.as_ref()?.start_span(req, name, kind)
+    }
+
+    fn revalidating_lock(&self) -> MutexGuard<'_, HashSet<Arc<str>>> {
+        self.revalidating
+            .lock()
+            .expect("revalidating lock poisoned!")
+    }
+
     fn create_http_client(timeout: Duration) -> Client {
         Client::builder().timeout(timeout).finish()
     }
 
-    fn build_cache_key(req: &HttpRequest) -> Arc<str> {
-        Arc::from(req.uri().to_string().as_str())
+    fn build_cache_key(req: &HttpRequest, policy: &CachePolicy) -> Arc<str> {
+        let mut key = match &policy.key_query_params {
+            Some(params) => {
+                let mut query = form_urlencoded::parse(req.query_string().as_bytes())
+                    .filter(|(name, _)| params.iter().any(|p| p == name))
+                    .map(|(name, value)| format!("{}={}", name, value))
+                    .collect::<Vec<String>>();
+                query.sort();
+                if query.is_empty() {
+                    req.path().to_string()
+                } else {
+                    format!("{}?{}", req.path(), query.join("&"))
+                }
+            }
+            None => req.uri().to_string(),
+        };
+
+        if let Some(names) = &policy.key_headers {
+            let names = names
+                .iter()
+                .map(|name| name.to_ascii_lowercase())
+                .collect::<Vec<String>>();
+            key = Self::build_variant_key(&key, &names, req.headers()).to_string();
+        }
+        Arc::from(key.as_str())
+    }
+
+    fn build_variant_key(key: &str, vary_names: &[String], req_headers: &HeaderMap) -> Arc<str> {
+        let mut variant_key = String::from(key);
+        for name in vary_names {
+            let values = req_headers
+                .get_all(name.as_str())
+                .filter_map(|v| v.to_str().ok())
+                .map(str::trim)
+                .collect::<Vec<&str>>()
+                .join(",");
+            variant_key.push_str(
+                format!(
+                    "|{}={}",
+                    Self::escape_key_part(name),
+                    Self::escape_key_part(&values)
+                )
+                .as_str(),
+            );
+        }
+        Arc::from(variant_key.as_str())
+    }
+
+    // separators are escaped so header values can't make keys of different variants equal
+    fn escape_key_part(part: &str) -> String {
+        part.replace('%', "%25")
+            .replace('|', "%7C")
+            .replace('=', "%3D")
     }
 
     // fn create_http_client(scheme: &str, timeout: Duration) -> Result<Client> {
@@ -187,3 +1133,694 @@ impl Proxy {
     //     }
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::http::header::{
+        CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE, SET_COOKIE, VARY,
+    };
+    use actix_web::http::{HeaderMap, HeaderValue, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web::{Bytes, BytesMut};
+    use actix_web::{web, App, HttpRequest, HttpResponse};
+    use futures::StreamExt;
+    use serde_json::Value;
+
+    use cache::{CacheEntryInfo, CacheStats, CacheStorage, CachedResponse, ResponseCache};
+
+    use crate::balancer::Balancer;
+    use crate::cache_status::{CacheOutcome, CacheStatus};
+    use crate::config::{CacheCompression, CachePolicy, Configuration, Encoding, Tracing};
+    use crate::metrics::Metrics;
+    use crate::proxy::Proxy;
+    use crate::trace::Tracer;
+
+    static CONFIG_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    fn proxy_to(upstream: &str) -> Proxy {
+        proxy_with_cache(upstream, Arc::new(ResponseCache::with_capacity(10)))
+    }
+
+    fn proxy_with_cache(upstream: &str, res_cache: Arc<dyn CacheStorage>) -> Proxy {
+        // tests run in parallel, each one writes its own config file
+        let path = std::env::temp_dir().join(format!(
+            "roxy-proxy-test-{}-{}.yaml",
+            std::process::id(),
+            CONFIG_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let yaml = format!(
+            "service: {{ip: localhost, port: 8080, workers: 1, dev_mode: true}}\n\
+             inbound: [{{path: /app/*, group: app}}]\n\
+             outbound: [{{group: app, servers: [{}]}}]\n",
+            upstream
+        );
+        std::fs::write(&path, yaml).unwrap();
+        let config = Configuration::new(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        Proxy::with_cache(Balancer::new(Arc::new(config)), res_cache)
+    }
+
+    // storage provided by application embedding the proxy
+    #[derive(Default)]
+    struct MapStorage {
+        entries: Mutex<HashMap<String, (CachedResponse, SystemTime)>>,
+    }
+
+    impl CacheStorage for MapStorage {
+        fn get(&self, k: &str) -> Option<CachedResponse> {
+            let entries = self.entries.lock().unwrap();
+            entries
+                .get(k)
+                .filter(|(_, expire_at)| *expire_at > SystemTime::now())
+                .map(|(res, _)| res.clone())
+        }
+
+        fn put(&self, k: Arc<str>, v: CachedResponse, expire_at: SystemTime) -> bool {
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(k.to_string(), (v, expire_at));
+            true
+        }
+
+        fn remove(&self, k: &str) -> bool {
+            self.entries.lock().unwrap().remove(k).is_some()
+        }
+
+        fn purge(&self, predicate: &dyn Fn(&str) -> bool) -> usize {
+            let mut entries = self.entries.lock().unwrap();
+            let len = entries.len();
+            entries.retain(|k, _| !predicate(k));
+            len - entries.len()
+        }
+
+        fn entry(&self, _k: &str) -> Option<CacheEntryInfo> {
+            None
+        }
+
+        fn entries(&self) -> Vec<CacheEntryInfo> {
+            vec![]
+        }
+
+        fn stats(&self) -> CacheStats {
+            CacheStats::default()
+        }
+    }
+
+    async fn read_body(mut res: HttpResponse) -> Bytes {
+        let mut body = res.take_body();
+        let mut bytes = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            bytes.extend_from_slice(&chunk.unwrap());
+        }
+        bytes.freeze()
+    }
+
+    #[actix_rt::test]
+    async fn should_store_variants_by_vary_header() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = test::start(move || {
+            let hits = upstream_hits.clone();
+            App::new().route(
+                "/app/page",
+                web::get().to(move |req: HttpRequest| {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    let language = req.headers().get("accept-language").unwrap();
+                    HttpResponse::Ok()
+                        .header(CACHE_CONTROL, "public, max-age=60")
+                        .header(VARY, "Accept-Language")
+                        .body(language.to_str().unwrap().to_string())
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        for (language, status) in &[
+            ("en", CacheStatus::Miss),
+            ("de", CacheStatus::Miss),
+            ("en", CacheStatus::Hit),
+            ("de", CacheStatus::Hit),
+        ] {
+            let req = TestRequest::get()
+                .uri("/app/page")
+                .header("Accept-Language", *language)
+                .to_http_request();
+            let res = proxy.clone().proxy(req, Bytes::new()).await.unwrap();
+            assert_eq!(*status, CacheOutcome::get(&res).unwrap().status);
+            assert_eq!(Bytes::from(*language), read_body(res).await);
+        }
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        // primary key holds only the Vary marker, bodies are stored once per variant
+        let cache = proxy.cache();
+        assert_eq!(0, cache.entry("/app/page").unwrap().size);
+        assert_eq!(3, cache.entries().len());
+        let stats = cache.stats();
+        assert_eq!(4, stats.size);
+        // one lookup per request, though variants take two reads
+        assert_eq!((2, 2), (stats.hits, stats.misses));
+    }
+
+    #[actix_rt::test]
+    async fn should_revalidate_stale_entry_with_upstream() {
+        let conditional = Arc::new(AtomicUsize::new(0));
+        let upstream_conditional = conditional.clone();
+        let upstream = test::start(move || {
+            let conditional = upstream_conditional.clone();
+            App::new().route(
+                "/app/doc",
+                web::get().to(move |req: HttpRequest| {
+                    // always stale so every request is revalidated
+                    let mut res = match req.headers().get(IF_NONE_MATCH) {
+                        Some(etag) if etag == "\"v1\"" => {
+                            conditional.fetch_add(1, Ordering::SeqCst);
+                            HttpResponse::NotModified()
+                        }
+                        _ => HttpResponse::Ok(),
+                    };
+                    res.header(ETAG, "\"v1\"")
+                        .header(CACHE_CONTROL, "public, max-age=0")
+                        .body("document")
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+        let get = || TestRequest::get().uri("/app/doc");
+
+        let res = proxy
+            .clone()
+            .proxy(get().to_http_request(), Bytes::new())
+            .await
+            .unwrap();
+        assert_eq!(CacheStatus::Miss, CacheOutcome::get(&res).unwrap().status);
+
+        // upstream confirms the stale entry, client gets the cached body
+        let res = proxy
+            .clone()
+            .proxy(get().to_http_request(), Bytes::new())
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            CacheStatus::Revalidated,
+            CacheOutcome::get(&res).unwrap().status
+        );
+        assert_eq!(Bytes::from("document"), read_body(res).await);
+
+        // client already has the representation
+        let req = get().header(IF_NONE_MATCH, "\"v1\"").to_http_request();
+        let res = proxy.proxy(req, Bytes::new()).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_eq!("\"v1\"", res.headers().get(ETAG).unwrap());
+        assert_eq!(2, conditional.load(Ordering::SeqCst));
+    }
+
+    #[actix_rt::test]
+    async fn should_keep_weak_etag_of_compressed_entry_on_revalidation() {
+        let upstream = test::start(|| {
+            App::new().route(
+                "/app/doc",
+                web::get().to(|req: HttpRequest| {
+                    let mut res = match req.headers().get(IF_NONE_MATCH) {
+                        Some(etag) if etag == "W/\"v1\"" => HttpResponse::NotModified(),
+                        _ => HttpResponse::Ok(),
+                    };
+                    res.header(ETAG, "\"v1\"")
+                        .header(CONTENT_TYPE, "text/plain")
+                        .header(CACHE_CONTROL, "public, max-age=0")
+                        .body("document")
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")).with_cache_compression(Some(
+            CacheCompression {
+                encoding: Encoding::Gzip,
+                content_types: vec!["text/".to_string()],
+                min_size: 0,
+            },
+        )));
+        let get = || TestRequest::get().uri("/app/doc").to_http_request();
+
+        proxy.clone().proxy(get(), Bytes::new()).await.unwrap();
+        let res = proxy.proxy(get(), Bytes::new()).await.unwrap();
+        assert_eq!(
+            CacheStatus::Revalidated,
+            CacheOutcome::get(&res).unwrap().status
+        );
+        assert_eq!("W/\"v1\"", res.headers().get(ETAG).unwrap());
+        assert_eq!(Bytes::from("document"), read_body(res).await);
+    }
+
+    // upstream answers with its hit count, failing once it's over the limit
+    fn counting_upstream(
+        hits: Arc<AtomicUsize>,
+        cache_control: &'static str,
+        failing_after: usize,
+    ) -> test::TestServer {
+        test::start(move || {
+            let hits = hits.clone();
+            App::new().route(
+                "/app/counter",
+                web::get().to(move || {
+                    let hit = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                    if hit > failing_after {
+                        return HttpResponse::InternalServerError().finish();
+                    }
+                    HttpResponse::Ok()
+                        .header(CACHE_CONTROL, cache_control)
+                        .body(hit.to_string())
+                }),
+            )
+        })
+    }
+
+    async fn get_counter(proxy: &Arc<Proxy>) -> (CacheStatus, Bytes) {
+        let req = TestRequest::get().uri("/app/counter").to_http_request();
+        let res = proxy.clone().proxy(req, Bytes::new()).await.unwrap();
+        let status = CacheOutcome::get(&res).unwrap().status;
+        (status, read_body(res).await)
+    }
+
+    #[actix_rt::test]
+    async fn should_record_requests_in_provided_metrics() {
+        let upstream = counting_upstream(Arc::new(AtomicUsize::new(0)), "max-age=60", usize::MAX);
+        let metrics = Arc::new(Metrics::new());
+        let proxy = Arc::new(proxy_to(&upstream.url("/")).with_metrics(metrics.clone()));
+
+        get_counter(&proxy).await;
+        let req = TestRequest::get().uri("/unknown").to_http_request();
+        assert!(proxy.clone().proxy(req, Bytes::new()).await.is_err());
+
+        let text = metrics.render(&*proxy.cache()).unwrap();
+        assert!(text.contains(r#"roxy_cache_requests_total{status="MISS"} 1"#));
+        // unrouted request has no route, group nor upstream
+        assert!(
+            text.contains(r#"roxy_requests_total{group="",route="",status="500",upstream=""} 1"#)
+        );
+    }
+
+    #[actix_rt::test]
+    async fn should_cache_in_provided_storage() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream = counting_upstream(hits.clone(), "public, max-age=60", usize::MAX);
+        let storage = Arc::new(MapStorage::default());
+        let proxy = Arc::new(proxy_with_cache(&upstream.url("/"), storage.clone()));
+
+        assert_eq!(
+            (CacheStatus::Miss, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        assert_eq!(
+            (CacheStatus::Hit, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        assert!(storage.get("/app/counter").is_some());
+    }
+
+    #[actix_rt::test]
+    async fn should_serve_stale_while_revalidating() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream = counting_upstream(
+            hits.clone(),
+            "public, max-age=0, stale-while-revalidate=60",
+            usize::MAX,
+        );
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        assert_eq!(
+            (CacheStatus::Miss, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        assert_eq!(
+            (CacheStatus::Stale, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        for _ in 0..100 {
+            if proxy.revalidating_lock().is_empty() {
+                break;
+            }
+            actix_rt::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(proxy.revalidating_lock().is_empty());
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        // background revalidation refreshed the entry
+        let (status, body) = get_counter(&proxy).await;
+        assert_eq!(CacheStatus::Stale, status);
+        assert_eq!(Bytes::from("2"), body);
+    }
+
+    #[actix_rt::test]
+    async fn should_serve_stale_if_upstream_fails() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream = counting_upstream(hits.clone(), "public, max-age=0, stale-if-error=60", 1);
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        assert_eq!(
+            (CacheStatus::Miss, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        assert_eq!(
+            (CacheStatus::Stale, Bytes::from("1")),
+            get_counter(&proxy).await
+        );
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+    }
+
+    #[actix_rt::test]
+    async fn should_share_upstream_response_with_identical_requests() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = test::start(move || {
+            let hits = upstream_hits.clone();
+            App::new().route(
+                "/app/slow",
+                web::get().to(move || {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    async {
+                        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+                        Ok::<_, actix_web::Error>(
+                            HttpResponse::Ok()
+                                .header(CACHE_CONTROL, "public, max-age=60")
+                                .header(SET_COOKIE, "session=abc")
+                                .body("slow"),
+                        )
+                    }
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        let requests = (0..5).map(|_| {
+            let req = TestRequest::get().uri("/app/slow").to_http_request();
+            proxy.clone().proxy(req, Bytes::new())
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+        let mut collapsed = 0;
+        for res in responses {
+            let res = res.unwrap();
+            if CacheOutcome::get(&res).unwrap().collapsed {
+                collapsed += 1;
+                assert!(res.headers().get(SET_COOKIE).is_none());
+            }
+            assert_eq!(Bytes::from("slow"), read_body(res).await);
+        }
+        assert_eq!(4, collapsed);
+    }
+
+    #[actix_rt::test]
+    async fn should_not_share_private_response_with_identical_requests() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = test::start(move || {
+            let hits = upstream_hits.clone();
+            App::new().route(
+                "/app/account",
+                web::get().to(move || {
+                    let hit = hits.fetch_add(1, Ordering::SeqCst) + 1;
+                    async move {
+                        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+                        Ok::<_, actix_web::Error>(
+                            HttpResponse::Ok()
+                                .header(CACHE_CONTROL, "private, max-age=60")
+                                .header(SET_COOKIE, format!("session={}", hit))
+                                .body(format!("account {}", hit)),
+                        )
+                    }
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        let requests = (0..2).map(|_| {
+            let req = TestRequest::get().uri("/app/account").to_http_request();
+            proxy.clone().proxy(req, Bytes::new())
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        // each client gets its own page and cookie
+        assert_eq!(2, hits.load(Ordering::SeqCst));
+        let mut pages = vec![];
+        for res in responses {
+            let res = res.unwrap();
+            assert!(!CacheOutcome::get(&res).unwrap().collapsed);
+            let cookie = res
+                .headers()
+                .get(SET_COOKIE)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            let page = String::from_utf8(read_body(res).await.to_vec()).unwrap();
+            assert_eq!(format!("account {}", &cookie["session=".len()..]), page);
+            pages.push(page);
+        }
+        pages.sort();
+        assert_eq!(vec!["account 1", "account 2"], pages);
+    }
+
+    fn headers(cache_control: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+        headers
+    }
+
+    #[test]
+    fn should_derive_ttl_from_headers_and_policy() {
+        let policy = CachePolicy {
+            default_ttl: Some(30),
+            max_ttl: Some(120),
+            ..CachePolicy::default()
+        };
+        let secs = |headers: &HeaderMap| Proxy::cache_ttl(&policy, headers).map(|d| d.as_secs());
+
+        assert_eq!(Some(30), secs(&HeaderMap::new()));
+        assert_eq!(Some(60), secs(&headers("public, max-age=60")));
+        assert_eq!(Some(120), secs(&headers("public, max-age=600")));
+        assert_eq!(None, secs(&headers("private, max-age=60")));
+        assert_eq!(None, secs(&headers("public, max-age=60, no-store")));
+        assert_eq!(None, secs(&headers("no-cache")));
+    }
+
+    #[test]
+    fn should_use_default_ttl_for_no_cache_when_ignored() {
+        let policy = CachePolicy {
+            default_ttl: Some(30),
+            ignore_no_cache: Some(true),
+            ..CachePolicy::default()
+        };
+
+        assert_eq!(
+            Some(Duration::from_secs(30)),
+            Proxy::cache_ttl(&policy, &headers("no-cache"))
+        );
+        assert_eq!(
+            Some(Duration::from_secs(60)),
+            Proxy::cache_ttl(&policy, &headers("public, max-age=60, no-cache"))
+        );
+        assert_eq!(
+            None,
+            Proxy::cache_ttl(&policy, &headers("no-cache, no-store"))
+        );
+    }
+
+    #[test]
+    fn should_build_cache_key_from_selected_params_and_headers() {
+        let req = TestRequest::get()
+            .uri("/app/list?page=2&sort=asc&lang=en")
+            .header("Accept-Language", "de")
+            .header("X-Other", "1")
+            .to_http_request();
+
+        let all = CachePolicy::default();
+        assert_eq!(
+            "/app/list?page=2&sort=asc&lang=en",
+            &*Proxy::build_cache_key(&req, &all)
+        );
+
+        let selected = CachePolicy {
+            key_query_params: Some(vec!["page".to_string(), "lang".to_string()]),
+            key_headers: Some(vec!["Accept-Language".to_string()]),
+            ..CachePolicy::default()
+        };
+        assert_eq!(
+            "/app/list?lang=en&page=2|accept-language=de",
+            &*Proxy::build_cache_key(&req, &selected)
+        );
+
+        let none = CachePolicy {
+            key_query_params: Some(vec![]),
+            ..CachePolicy::default()
+        };
+        assert_eq!("/app/list", &*Proxy::build_cache_key(&req, &none));
+    }
+
+    #[test]
+    fn should_escape_variant_key_separators() {
+        let names = vec!["a".to_string(), "b".to_string()];
+        let first = TestRequest::default()
+            .header("a", "1|b=2")
+            .to_http_request();
+        let second = TestRequest::default()
+            .header("a", "1")
+            .header("b", "2|b=")
+            .to_http_request();
+
+        let first = Proxy::build_variant_key("/", &names, first.headers());
+        let second = Proxy::build_variant_key("/", &names, second.headers());
+        assert_eq!("/|a=1%7Cb%3D2|b=", &*first);
+        assert_ne!(first, second);
+    }
+
+    #[actix_rt::test]
+    async fn should_forward_range_of_response_not_expected_to_be_stored() {
+        let upstream = test::start(|| {
+            App::new().route(
+                "/app/video",
+                web::get().to(|req: HttpRequest| match req.headers().get(RANGE) {
+                    Some(range) => {
+                        assert_eq!("bytes=0-1", range);
+                        HttpResponse::PartialContent()
+                            .header(CONTENT_RANGE, "bytes 0-1/4")
+                            .body("vi")
+                    }
+                    None => HttpResponse::Ok().body("full"),
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        let req = TestRequest::get()
+            .uri("/app/video")
+            .header(RANGE, "bytes=0-1")
+            .to_http_request();
+        let res = proxy.clone().proxy(req, Bytes::new()).await.unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.status());
+        assert_eq!("bytes 0-1/4", res.headers().get(CONTENT_RANGE).unwrap());
+        assert_eq!(Bytes::from("vi"), read_body(res).await);
+        assert_eq!(0, proxy.cache().stats().entries);
+    }
+
+    #[actix_rt::test]
+    async fn should_relay_upstream_response_headers() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let upstream_hits = hits.clone();
+        let upstream = test::start(move || {
+            let hits = upstream_hits.clone();
+            App::new().route(
+                "/app/data",
+                web::get().to(move || {
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    HttpResponse::Ok()
+                        .content_type("application/json")
+                        .header(SET_COOKIE, "session=abc; Path=/")
+                        .header(SET_COOKIE, "theme=dark")
+                        .header(CACHE_CONTROL, "public, max-age=60")
+                        .header("Keep-Alive", "timeout=5")
+                        .body("{}")
+                }),
+            )
+        });
+        let proxy = Arc::new(proxy_to(&upstream.url("/")));
+
+        let req = TestRequest::get().uri("/app/data").to_http_request();
+        let res = proxy.clone().proxy(req, Bytes::new()).await.unwrap();
+        let headers = res.headers();
+        assert_eq!("application/json", headers.get(CONTENT_TYPE).unwrap());
+        let mut cookies = headers
+            .get_all(SET_COOKIE)
+            .map(|v| v.to_str().unwrap())
+            .collect::<Vec<&str>>();
+        cookies.sort_unstable();
+        assert_eq!(vec!["session=abc; Path=/", "theme=dark"], cookies);
+        assert_eq!("public, max-age=60", headers.get(CACHE_CONTROL).unwrap());
+        assert!(headers.get("keep-alive").is_none());
+
+        // max-age is honoured so the second request doesn't reach upstream,
+        // cookies of the first client aren't served from cache
+        let req = TestRequest::get().uri("/app/data").to_http_request();
+        let res = proxy.proxy(req, Bytes::new()).await.unwrap();
+        assert_eq!(CacheStatus::Hit, CacheOutcome::get(&res).unwrap().status);
+        assert_eq!("application/json", res.headers().get(CONTENT_TYPE).unwrap());
+        assert!(res.headers().get(SET_COOKIE).is_none());
+        assert_eq!(1, hits.load(Ordering::SeqCst));
+    }
+
+    #[actix_rt::test]
+    async fn should_export_spans_of_proxied_request() {
+        let traceparents = Arc::new(Mutex::new(vec![]));
+        let upstream_traceparents = traceparents.clone();
+        let upstream = test::start(move || {
+            let traceparents = upstream_traceparents.clone();
+            App::new().route(
+                "/app/data",
+                web::get().to(move |req: HttpRequest| {
+                    let traceparent = req.headers().get("traceparent").unwrap();
+                    traceparents
+                        .lock()
+                        .unwrap()
+                        .push(traceparent.to_str().unwrap().to_string());
+                    HttpResponse::Ok().body("data")
+                }),
+            )
+        });
+        let exports = Arc::new(Mutex::new(vec![]));
+        let collector_exports = exports.clone();
+        let collector = test::start(move || {
+            let exports = collector_exports.clone();
+            App::new().route(
+                "/v1/traces",
+                web::post().to(move |body: web::Json<Value>| {
+                    exports.lock().unwrap().push(body.into_inner());
+                    HttpResponse::Ok().finish()
+                }),
+            )
+        });
+        let tracer = Arc::new(Tracer::new(Tracing {
+            endpoint: collector.url("/v1/traces"),
+            ..Tracing::default()
+        }));
+        let proxy = Arc::new(proxy_to(&upstream.url("/")).with_tracer(Some(tracer.clone())));
+
+        let req = TestRequest::get()
+            .uri("/app/data")
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .to_http_request();
+        proxy.proxy(req, Bytes::new()).await.unwrap();
+        tracer.export().await.unwrap();
+
+        let exports = exports.lock().unwrap();
+        let spans = exports[0]["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap();
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span["name"] == name)
+                .unwrap_or_else(|| panic!("missing {} span", name))
+        };
+        let server = span("GET");
+        assert_eq!("b7ad6b7169203331", server["parentSpanId"]);
+        for name in &["route", "cache lookup", "upstream"] {
+            assert_eq!("0af7651916cd43dd8448eb211c80319c", span(name)["traceId"]);
+            assert_eq!(server["spanId"], span(name)["parentSpanId"]);
+        }
+        // upstream continues the trace from roxy's client span
+        assert_eq!(
+            format!(
+                "00-0af7651916cd43dd8448eb211c80319c-{}-01",
+                span("upstream")["spanId"].as_str().unwrap()
+            ),
+            traceparents.lock().unwrap()[0]
+        );
+    }
+}
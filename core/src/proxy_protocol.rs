@@ -0,0 +1,178 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::forwarded::TrustedProxies;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// Reads PROXY protocol (v1 or v2) header sent ahead of the http request and returns client address it carries.
+/// None is returned for LOCAL and UNKNOWN connections, e.g. load balancer health checks.
+pub async fn read_proxy_header<T>(io: &mut T) -> io::Result<Option<SocketAddr>>
+where
+    T: AsyncRead + Unpin,
+{
+    // shortest v1 header is 15 bytes long so reading 12 can't consume http request
+    let mut start = [0u8; 12];
+    io.read_exact(&mut start).await?;
+    if start == V2_SIGNATURE {
+        let mut header = [0u8; 4];
+        io.read_exact(&mut header).await?;
+        let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let mut addresses = vec![0u8; len];
+        io.read_exact(&mut addresses).await?;
+        parse_v2(header[0], header[1], &addresses)
+    } else if start.starts_with(V1_PREFIX) {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            let mut byte = [0u8; 1];
+            io.read_exact(&mut byte).await?;
+            line.push(byte[0]);
+        }
+        parse_v1(&line)
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+/// Client address of connection from trusted proxy, read from its PROXY protocol header or the peer address
+/// when header doesn't carry one. Connections of other peers are rejected as their header can't be trusted.
+pub async fn read_trusted_proxy_header<T>(
+    io: &mut T,
+    peer: Option<SocketAddr>,
+    trusted_proxies: &TrustedProxies,
+) -> io::Result<Option<SocketAddr>>
+where
+    T: AsyncRead + Unpin,
+{
+    match peer {
+        Some(peer) if trusted_proxies.trusts(peer.ip()) => {
+            Ok(read_proxy_header(io).await?.or(Some(peer)))
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "PROXY header from untrusted peer",
+        )),
+    }
+}
+
+// PROXY TCP4 <src ip> <dst ip> <src port> <dst port>\r\n
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("malformed PROXY v1 header"))?;
+    let mut parts = line.trim_end().split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4") | Some("TCP6") => {
+            let source: IpAddr = v1_part(parts.next())?;
+            let _destination: IpAddr = v1_part(parts.next())?;
+            let port: u16 = v1_part(parts.next())?;
+            Ok(Some(SocketAddr::new(source, port)))
+        }
+        Some("UNKNOWN") => Ok(None),
+        _ => Err(invalid("unsupported PROXY v1 protocol")),
+    }
+}
+
+fn v1_part<F: FromStr>(part: Option<&str>) -> io::Result<F> {
+    part.and_then(|p| p.parse().ok())
+        .ok_or_else(|| invalid("malformed PROXY v1 header"))
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match version_command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(invalid("unsupported PROXY v2 version or command")),
+    }
+    match family {
+        V2_TCP4 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        V2_TCP6 if addresses.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addresses[..16]);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // udp and unix sockets don't carry usable client address
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::forwarded::TrustedProxies;
+    use crate::proxy_protocol::{read_proxy_header, read_trusted_proxy_header};
+
+    #[test]
+    fn should_read_v1_header() {
+        let mut input: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\nGET / HTTP/1.1\r\n";
+        let client = block_on(read_proxy_header(&mut input)).unwrap();
+
+        assert_eq!(Some("203.0.113.7:51000".parse().unwrap()), client);
+        assert_eq!(&b"GET / HTTP/1.1\r\n"[..], input);
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        let client = block_on(read_proxy_header(&mut input)).unwrap();
+        assert_eq!(None, client);
+    }
+
+    #[test]
+    fn should_read_v2_header() {
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n".to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend_from_slice(&51000u16.to_be_bytes());
+        header.extend_from_slice(&80u16.to_be_bytes());
+        header.extend_from_slice(b"GET");
+        let mut input = &header[..];
+
+        let client = block_on(read_proxy_header(&mut input)).unwrap();
+        assert_eq!(Some("203.0.113.7:51000".parse().unwrap()), client);
+        assert_eq!(&b"GET"[..], input);
+    }
+
+    #[test]
+    fn should_reject_missing_header() {
+        let mut input: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\n\r\n";
+        assert!(block_on(read_proxy_header(&mut input)).is_err());
+    }
+
+    #[test]
+    fn should_read_header_of_trusted_proxy_only() {
+        let trusted = TrustedProxies::new(&["10.0.0.0/8".to_string()]).unwrap();
+        let header: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51000 80\r\nGET / HTTP/1.1\r\n";
+
+        let mut input = header;
+        let peer = Some("10.0.0.2:40000".parse().unwrap());
+        let client = block_on(read_trusted_proxy_header(&mut input, peer, &trusted)).unwrap();
+        assert_eq!(Some("203.0.113.7:51000".parse().unwrap()), client);
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        let client = block_on(read_trusted_proxy_header(&mut input, peer, &trusted)).unwrap();
+        assert_eq!(peer, client);
+
+        let mut input = header;
+        let peer = Some("203.0.113.9:40000".parse().unwrap());
+        assert!(block_on(read_trusted_proxy_header(&mut input, peer, &trusted)).is_err());
+        assert!(block_on(read_trusted_proxy_header(&mut input, None, &trusted)).is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::dev::SizedStream;
+use actix_web::http::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, LAST_MODIFIED,
+    RANGE,
+};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use futures::stream::{self, StreamExt};
+
+use cache::CachedBody;
+
+use crate::file_body::{body_stream, BodyStream};
+use crate::http_utils::{Headers, WEAK_ETAG_PREFIX};
+
+const BYTES_UNIT: &str = "bytes=";
+// more ranges than this are answered with the full body
+const MAX_RANGES: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum ByteRanges {
+    // start inclusive, end exclusive
+    Satisfiable(Vec<(u64, u64)>),
+    Unsatisfiable,
+}
+
+/// Parses `Range: bytes=...` (RFC 7233), None if header is invalid and should be ignored.
+pub fn parse_ranges(value: &str, len: u64) -> Option<ByteRanges> {
+    let specs = value.trim().strip_prefix(BYTES_UNIT)?;
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let (first, last) = spec.split_once('-')?;
+        let (first, last) = (first.trim(), last.trim());
+        let range = if first.is_empty() {
+            let suffix = last.parse::<u64>().ok()?;
+            (suffix > 0).then(|| (len.saturating_sub(suffix), len))
+        } else {
+            let first = first.parse::<u64>().ok()?;
+            let last = if last.is_empty() {
+                len
+            } else {
+                let last = last.parse::<u64>().ok()?;
+                if last < first {
+                    return None;
+                }
+                (last + 1).min(len)
+            };
+            (first < len).then_some((first, last))
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        None
+    } else if ranges.is_empty() {
+        Some(ByteRanges::Unsatisfiable)
+    } else {
+        Some(ByteRanges::Satisfiable(ranges))
+    }
+}
+
+/// Serves Range request from a complete body, None when full response should be sent.
+pub fn partial_response(
+    req: &HttpRequest,
+    status: StatusCode,
+    headers: &HeaderMap,
+    body: &CachedBody,
+) -> Option<HttpResponse> {
+    if status != StatusCode::OK || req.method() != Method::GET {
+        return None;
+    }
+    let req_headers = req.headers();
+    let range = req_headers.get_header_value(RANGE.as_str())?;
+    if !if_range_matches(req_headers, headers) {
+        return None;
+    }
+
+    let len = body.len() as u64;
+    let ranges = match parse_ranges(range, len)? {
+        ByteRanges::Satisfiable(ranges) => ranges,
+        ByteRanges::Unsatisfiable => {
+            return Some(
+                HttpResponse::RangeNotSatisfiable()
+                    .header(CONTENT_RANGE, format!("bytes */{}", len))
+                    .finish(),
+            );
+        }
+    };
+
+    let mut response = if let [(start, end)] = ranges[..] {
+        let mut response = HttpResponse::PartialContent().body(SizedStream::new(
+            end - start,
+            body_stream(body.slice(start, end - start)),
+        ));
+        *response.headers_mut() = headers.clone();
+        response.headers_mut().insert(
+            CONTENT_RANGE,
+            HeaderValue::from_str(&content_range(start, end, len)).ok()?,
+        );
+        response
+    } else {
+        multipart_response(headers, body, &ranges)
+    };
+    response.headers_mut().remove(CONTENT_LENGTH);
+    Some(response)
+}
+
+fn multipart_response(
+    headers: &HeaderMap,
+    body: &CachedBody,
+    ranges: &[(u64, u64)],
+) -> HttpResponse {
+    let boundary = boundary();
+    let content_type = headers.get_header_value(CONTENT_TYPE.as_str());
+    let mut parts: Vec<BodyStream> = Vec::with_capacity(ranges.len() * 2 + 1);
+    let mut size = 0;
+    for (start, end) in ranges {
+        let mut part_head = format!("\r\n--{}\r\n", boundary);
+        if let Some(content_type) = content_type {
+            part_head.push_str(&format!("Content-Type: {}\r\n", content_type));
+        }
+        part_head.push_str(&format!(
+            "Content-Range: {}\r\n\r\n",
+            content_range(*start, *end, body.len() as u64)
+        ));
+        size += part_head.len() as u64 + (end - start);
+        parts.push(body_stream(Bytes::from(part_head).into()));
+        parts.push(body_stream(body.slice(*start, end - start)));
+    }
+    let closing = format!("\r\n--{}--\r\n", boundary);
+    size += closing.len() as u64;
+    parts.push(body_stream(Bytes::from(closing).into()));
+
+    let mut response = HttpResponse::PartialContent().body(SizedStream::new(
+        size,
+        Box::pin(stream::iter(parts).flatten()) as BodyStream,
+    ));
+    *response.headers_mut() = headers.clone();
+    if let Ok(value) =
+        HeaderValue::from_str(&format!("multipart/byteranges; boundary={}", boundary))
+    {
+        response.headers_mut().insert(CONTENT_TYPE, value);
+    }
+    response
+}
+
+// If-Range validator has to match strongly, otherwise full body is sent
+fn if_range_matches(req_headers: &HeaderMap, res_headers: &HeaderMap) -> bool {
+    let if_range = match req_headers.get_header_value(IF_RANGE.as_str()) {
+        Some(if_range) => if_range.trim(),
+        None => return true,
+    };
+    if if_range.starts_with('"') {
+        res_headers
+            .etag()
+            .is_some_and(|etag| !etag.starts_with(WEAK_ETAG_PREFIX) && etag == if_range)
+    } else {
+        res_headers
+            .get_header_value(LAST_MODIFIED.as_str())
+            .is_some_and(|last_modified| last_modified == if_range)
+    }
+}
+
+fn content_range(start: u64, end: u64, len: u64) -> String {
+    format!("bytes {}-{}/{}", start, end - 1, len)
+}
+
+fn boundary() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos());
+    format!("roxy{:032x}", nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::header::{HeaderMap, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+    use actix_web::http::StatusCode;
+    use actix_web::rt::System;
+    use actix_web::test::{read_body, TestRequest};
+    use actix_web::web::Bytes;
+
+    use cache::CachedBody;
+
+    use crate::range::{parse_ranges, partial_response, ByteRanges};
+
+    #[test]
+    fn should_parse_byte_ranges() {
+        assert_eq!(
+            Some(ByteRanges::Satisfiable(vec![
+                (0, 500),
+                (500, 1000),
+                (900, 1000)
+            ])),
+            parse_ranges("bytes=0-499, 500-, -100", 1000)
+        );
+        assert_eq!(
+            Some(ByteRanges::Satisfiable(vec![(990, 1000)])),
+            parse_ranges("bytes=990-2000", 1000)
+        );
+        assert_eq!(
+            Some(ByteRanges::Unsatisfiable),
+            parse_ranges("bytes=1000-", 1000)
+        );
+        assert_eq!(None, parse_ranges("bytes=500-100", 1000));
+        assert_eq!(None, parse_ranges("items=0-1", 1000));
+    }
+
+    #[test]
+    fn should_serve_partial_content() {
+        let body: CachedBody = Bytes::from_static(b"0123456789").into();
+        let headers = HeaderMap::new();
+        let req = TestRequest::default()
+            .header(RANGE, "bytes=2-4")
+            .to_http_request();
+        let res = partial_response(&req, StatusCode::OK, &headers, &body).unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.status());
+        assert_eq!("bytes 2-4/10", res.headers().get(CONTENT_RANGE).unwrap());
+        let bytes = System::new("test").block_on(read_body(ServiceResponse::new(req, res)));
+        assert_eq!(Bytes::from_static(b"234"), bytes);
+
+        let req = TestRequest::default()
+            .header(RANGE, "bytes=0-1,-2")
+            .to_http_request();
+        let res = partial_response(&req, StatusCode::OK, &headers, &body).unwrap();
+        let content_type = res.headers().get(CONTENT_TYPE).unwrap().to_str().unwrap();
+        let boundary = content_type.split("boundary=").nth(1).unwrap().to_string();
+        let bytes = System::new("test").block_on(read_body(ServiceResponse::new(req, res)));
+        let expected = format!(
+            "\r\n--{b}\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(Bytes::from(expected), bytes);
+
+        let req = TestRequest::default()
+            .header(RANGE, "bytes=10-")
+            .to_http_request();
+        let res = partial_response(&req, StatusCode::OK, &headers, &body).unwrap();
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, res.status());
+    }
+}
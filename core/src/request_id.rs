@@ -0,0 +1,150 @@
+use std::convert::TryFrom;
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use futures::future::{ok, Either, LocalBoxFuture, Ready};
+use uuid::Uuid;
+
+// longer ids sent by clients are replaced with generated one
+const MAX_REQUEST_ID_LEN: usize = 200;
+
+tokio::task_local! {
+    static REQUEST_ID: Arc<str>;
+}
+
+/// Id of the request handled by current task, if any.
+pub fn current_request_id() -> Option<Arc<str>> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Runs future with request id of current task so its logs can be correlated to the request.
+pub(crate) fn with_current_request_id<F: Future>(f: F) -> impl Future<Output = F::Output> {
+    match current_request_id() {
+        Some(id) => Either::Left(REQUEST_ID.scope(id, f)),
+        None => Either::Right(f),
+    }
+}
+
+/// Middleware accepting request id sent by client or generating a new one.
+/// Id is set on the request (forwarded upstream), echoed in the response and is available
+/// to every log line emitted while handling the request.
+#[derive(Clone)]
+pub struct RequestId {
+    header: HeaderName,
+}
+
+impl RequestId {
+    pub fn new(header: &str) -> anyhow::Result<Self> {
+        let header = HeaderName::try_from(header)
+            .map_err(|e| anyhow::anyhow!("Invalid request id header {}. Err = {}", header, e))?;
+        Ok(RequestId { header })
+    }
+}
+
+impl<S, B> Transform<S> for RequestId
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = RequestIdMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ok(RequestIdMiddleware {
+            service,
+            header: self.header.clone(),
+        })
+    }
+}
+
+pub struct RequestIdMiddleware<S> {
+    service: S,
+    header: HeaderName,
+}
+
+impl<S, B> Service for RequestIdMiddleware<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let id = req
+            .headers()
+            .get(&self.header)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| valid_request_id(id))
+            .map_or_else(|| Uuid::new_v4().to_string(), |id| id.to_string());
+        let value = HeaderValue::from_str(&id).expect("request id is a valid header value");
+        req.headers_mut().insert(self.header.clone(), value.clone());
+
+        let header = self.header.clone();
+        let res = REQUEST_ID.scope(Arc::from(id), self.service.call(req));
+        Box::pin(async move {
+            let mut res = res.await?;
+            res.headers_mut().insert(header, value);
+            Ok(res)
+        })
+    }
+}
+
+fn valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, web, App, HttpResponse};
+
+    use crate::request_id::{current_request_id, RequestId};
+
+    async fn echo_id() -> HttpResponse {
+        HttpResponse::Ok().body(current_request_id().unwrap().to_string())
+    }
+
+    #[actix_rt::test]
+    async fn should_accept_or_generate_request_id() {
+        let mut app = test::init_service(
+            App::new()
+                .wrap(RequestId::new("X-Request-ID").unwrap())
+                .route("/", web::get().to(echo_id)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header("x-request-id", "abc-123")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        assert_eq!("abc-123", res.headers().get("x-request-id").unwrap());
+        assert_eq!("abc-123", test::read_body(res).await);
+
+        let req = test::TestRequest::get()
+            .uri("/")
+            .header("x-request-id", "bad id")
+            .to_request();
+        let res = test::call_service(&mut app, req).await;
+        let id = res.headers().get("x-request-id").unwrap().clone();
+        assert_eq!(36, id.len());
+        assert_eq!(id.as_bytes(), &test::read_body(res).await[..]);
+        assert!(current_request_id().is_none());
+    }
+}
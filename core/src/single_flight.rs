@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use futures::channel::oneshot::{channel, Receiver, Sender};
+
+type Waiters<T> = Vec<Sender<T>>;
+
+/// Tracks upstream requests in flight per key.
+/// Followers receive the value leader completes with, or are cancelled when leader's guard is dropped.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<Arc<str>, Waiters<T>>>,
+}
+
+pub enum Flight<'a, T: Clone> {
+    Leader(FlightGuard<'a, T>),
+    Follower(Receiver<T>),
+}
+
+pub struct FlightGuard<'a, T: Clone> {
+    flights: &'a SingleFlight<T>,
+    key: Arc<str>,
+    completed: bool,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn join(&self, key: Arc<str>) -> Flight<'_, T> {
+        let mut in_flight = self.in_flight_lock();
+        if let Some(waiters) = in_flight.get_mut(&key) {
+            let (tx, rx) = channel();
+            waiters.push(tx);
+            Flight::Follower(rx)
+        } else {
+            in_flight.insert(key.clone(), vec![]);
+            Flight::Leader(FlightGuard {
+                flights: self,
+                key,
+                completed: false,
+            })
+        }
+    }
+
+    fn in_flight_lock(&self) -> MutexGuard<'_, HashMap<Arc<str>, Waiters<T>>> {
+        self.in_flight.lock().expect("in flight lock poisoned!")
+    }
+}
+
+impl<T: Clone> FlightGuard<'_, T> {
+    /// Sends value to all followers, requests joining afterwards start a new flight.
+    pub fn complete(mut self, value: &T) {
+        self.completed = true;
+        let waiters = self.flights.in_flight_lock().remove(&self.key);
+        for waiter in waiters.into_iter().flatten() {
+            // follower may have stopped waiting
+            let _ = waiter.send(value.clone());
+        }
+    }
+}
+
+impl<T: Clone> Drop for FlightGuard<'_, T> {
+    // dropping the senders cancels all waiting receivers
+    fn drop(&mut self) {
+        if !self.completed {
+            self.flights.in_flight_lock().remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures::channel::oneshot::Receiver;
+    use futures::executor::block_on;
+
+    use crate::single_flight::{Flight, SingleFlight};
+
+    fn follower(flights: &SingleFlight<u32>, key: Arc<str>) -> Receiver<u32> {
+        match flights.join(key) {
+            Flight::Follower(rx) => rx,
+            Flight::Leader(_) => panic!("Expected follower"),
+        }
+    }
+
+    #[test]
+    fn should_share_leader_value_with_followers() {
+        let flights = SingleFlight::new();
+        let key: Arc<str> = Arc::from("1");
+
+        let leader = match flights.join(key.clone()) {
+            Flight::Leader(guard) => guard,
+            Flight::Follower(_) => panic!("Expected leader"),
+        };
+        let first = follower(&flights, key.clone());
+        let second = follower(&flights, key.clone());
+
+        leader.complete(&7);
+        assert_eq!(Ok(7), block_on(first));
+        assert_eq!(Ok(7), block_on(second));
+        assert!(matches!(flights.join(key), Flight::Leader(_)));
+    }
+
+    #[test]
+    fn should_cancel_followers_when_leader_gives_up() {
+        let flights = SingleFlight::new();
+        let key: Arc<str> = Arc::from("1");
+
+        let leader = flights.join(key.clone());
+        assert!(matches!(leader, Flight::Leader(_)));
+        let follower = follower(&flights, key.clone());
+
+        drop(leader);
+        assert!(block_on(follower).is_err());
+        assert!(matches!(flights.join(key), Flight::Leader(_)));
+    }
+}
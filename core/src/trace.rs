@@ -0,0 +1,393 @@
+use std::fmt::Write;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::client::Client;
+use actix_web::http::HeaderMap;
+use actix_web::HttpRequest;
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde_json::{json, Value};
+
+use crate::config::Tracing;
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(10);
+const SAMPLED_FLAG: u8 = 0x01;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+/// Trace of the request being proxied, kept in request extensions.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TraceContext {
+    pub trace_id: [u8; 16],
+    // span of roxy handling the request, parent of other spans
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+    pub tracestate: Option<String>,
+}
+
+impl TraceContext {
+    /// Parses W3C traceparent header, None if missing or invalid.
+    fn from_traceparent(headers: &HeaderMap) -> Option<(TraceContext, [u8; 8])> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?;
+        let parts = traceparent.trim().split('-').collect::<Vec<&str>>();
+        // future versions may add fields
+        let version = from_hex::<1>(parts.first()?)?;
+        if version[0] == 0xff || (version[0] == 0 && parts.len() != 4) || parts.len() < 4 {
+            return None;
+        }
+        let trace_id = from_hex::<16>(parts[1])?;
+        let parent_id = from_hex::<8>(parts[2])?;
+        let flags = from_hex::<1>(parts[3])?;
+        if trace_id == [0; 16] || parent_id == [0; 8] {
+            return None;
+        }
+        let tracestate = headers
+            .get(TRACESTATE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let ctx = TraceContext {
+            trace_id,
+            span_id: rand::random(),
+            sampled: flags[0] & SAMPLED_FLAG != 0,
+            tracestate,
+        };
+        Some((ctx, parent_id))
+    }
+
+    fn get(req: &HttpRequest) -> Option<TraceContext> {
+        req.extensions().get::<TraceContext>().cloned()
+    }
+}
+
+#[derive(Debug)]
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    tracestate: Option<String>,
+    name: String,
+    kind: SpanKind,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+/// Span is finished and queued for export when dropped.
+pub(crate) struct Span {
+    data: SpanData,
+    // not set for spans which are not sampled
+    tracer: Option<Arc<Tracer>>,
+}
+
+impl Span {
+    pub fn set_attribute<V: ToString>(&mut self, key: &'static str, value: V) {
+        if self.tracer.is_some() {
+            self.data.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn set_error<E: ToString>(&mut self, error: E) {
+        if self.tracer.is_some() {
+            self.data.error = Some(error.to_string());
+        }
+    }
+
+    /// W3C traceparent with this span as the parent, propagated to upstream.
+    pub fn traceparent(&self) -> String {
+        let flags = if self.tracer.is_some() {
+            SAMPLED_FLAG
+        } else {
+            0
+        };
+        format!(
+            "00-{}-{}-{:02x}",
+            to_hex(&self.data.trace_id),
+            to_hex(&self.data.span_id),
+            flags
+        )
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(tracer) = self.tracer.take() {
+            self.data.end = SystemTime::now();
+            let data = mem::replace(&mut self.data, SpanData::empty());
+            tracer.record(data);
+        }
+    }
+}
+
+impl SpanData {
+    fn empty() -> Self {
+        SpanData {
+            trace_id: [0; 16],
+            span_id: [0; 8],
+            parent_span_id: None,
+            tracestate: None,
+            name: String::new(),
+            kind: SpanKind::Internal,
+            start: UNIX_EPOCH,
+            end: UNIX_EPOCH,
+            attributes: vec![],
+            error: None,
+        }
+    }
+
+    fn to_otlp(&self) -> Value {
+        let mut span = json!({
+            "traceId": to_hex(&self.trace_id),
+            "spanId": to_hex(&self.span_id),
+            "name": self.name,
+            "kind": self.kind as u8,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": attributes(&self.attributes),
+            "status": match &self.error {
+                Some(message) => json!({"code": 2, "message": message}),
+                None => json!({"code": 0}),
+            },
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(to_hex(parent));
+        }
+        if let Some(tracestate) = &self.tracestate {
+            span["traceState"] = json!(tracestate);
+        }
+        span
+    }
+}
+
+/// Creates spans of proxied requests and exports finished ones to OTLP collector.
+pub struct Tracer {
+    config: Tracing,
+    queue: Mutex<Vec<SpanData>>,
+}
+
+impl Tracer {
+    pub fn new(config: Tracing) -> Self {
+        Tracer {
+            config,
+            queue: Mutex::new(vec![]),
+        }
+    }
+
+    /// Starts span of roxy handling the request, continuing trace from traceparent header if valid.
+    /// New traces are sampled by configured ratio, others follow caller's decision.
+    pub(crate) fn start_request(self: &Arc<Self>, req: &HttpRequest) -> Span {
+        let (ctx, parent) = match TraceContext::from_traceparent(req.headers()) {
+            Some((ctx, parent)) => (ctx, Some(parent)),
+            None => {
+                let ctx = TraceContext {
+                    trace_id: rand::random(),
+                    span_id: rand::random(),
+                    sampled: rand::random::<f64>() < self.config.sample_ratio,
+                    tracestate: None,
+                };
+                (ctx, None)
+            }
+        };
+        req.extensions_mut().insert(ctx.clone());
+
+        let mut span = self.span(&ctx, ctx.span_id, parent, req.method().to_string());
+        span.data.kind = SpanKind::Server;
+        span.set_attribute("http.method", req.method());
+        span.set_attribute("http.target", req.uri());
+        span
+    }
+
+    /// Starts child span of request span, None if request is not traced.
+    pub(crate) fn start_span(
+        self: &Arc<Self>,
+        req: &HttpRequest,
+        name: &str,
+        kind: SpanKind,
+    ) -> Option<Span> {
+        let ctx = TraceContext::get(req)?;
+        let mut span = self.span(&ctx, rand::random(), Some(ctx.span_id), name.to_string());
+        span.data.kind = kind;
+        Some(span)
+    }
+
+    fn span(
+        self: &Arc<Self>,
+        ctx: &TraceContext,
+        span_id: [u8; 8],
+        parent_span_id: Option<[u8; 8]>,
+        name: String,
+    ) -> Span {
+        Span {
+            data: SpanData {
+                trace_id: ctx.trace_id,
+                span_id,
+                parent_span_id,
+                tracestate: ctx.tracestate.clone(),
+                name,
+                start: SystemTime::now(),
+                ..SpanData::empty()
+            },
+            tracer: Some(self.clone()).filter(|_| ctx.sampled),
+        }
+    }
+
+    fn record(&self, span: SpanData) {
+        let mut queue = self.queue_lock();
+        if queue.len() < self.config.max_queue_size {
+            queue.push(span);
+        }
+    }
+
+    /// Sends finished spans to the collector.
+    pub async fn export(&self) -> Result<()> {
+        let spans = mem::take(&mut *self.queue_lock());
+        if spans.is_empty() {
+            return Ok(());
+        }
+        let request = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": attributes(&[("service.name", self.config.service_name.clone())]),
+                },
+                "scopeSpans": [{
+                    "scope": {"name": "roxy"},
+                    "spans": spans.iter().map(SpanData::to_otlp).collect::<Vec<Value>>(),
+                }],
+            }],
+        });
+        let res = Client::builder()
+            .timeout(EXPORT_TIMEOUT)
+            .finish()
+            .post(&self.config.endpoint)
+            .send_json(&request)
+            .await
+            .map_err(|e| {
+                anyhow!(
+                    "Error sending spans to {}, {} spans dropped. Err = {}",
+                    self.config.endpoint,
+                    spans.len(),
+                    e
+                )
+            })?;
+        if !res.status().is_success() {
+            return Err(anyhow!(
+                "Collector {} rejected {} spans with status {}, spans dropped",
+                self.config.endpoint,
+                spans.len(),
+                res.status()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Exports finished spans every configured interval.
+    pub async fn export_periodically(self: Arc<Self>) {
+        let mut interval =
+            actix_web::rt::time::interval(Duration::from_secs(self.config.export_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.export().await {
+                warn!("{}", e);
+            }
+        }
+    }
+
+    fn queue_lock(&self) -> MutexGuard<'_, Vec<SpanData>> {
+        self.queue.lock().expect("span queue lock poisoned!")
+    }
+}
+
+fn attributes(attributes: &[(&'static str, String)]) -> Vec<Value> {
+    attributes
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
+// only lowercase hex is valid in traceparent
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::test::TestRequest;
+
+    use crate::config::Tracing;
+    use crate::trace::{TraceContext, Tracer};
+
+    #[test]
+    fn should_continue_valid_traceparent() {
+        let tracer = Arc::new(Tracer::new(Tracing {
+            sample_ratio: 0.0,
+            ..Tracing::default()
+        }));
+        let req = TestRequest::get()
+            .header(
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .header("tracestate", "congo=t61rcWkgMzE")
+            .to_http_request();
+        let span = tracer.start_request(&req);
+        let ctx = TraceContext::get(&req).unwrap();
+        assert!(ctx.sampled);
+        assert_eq!(Some("congo=t61rcWkgMzE".to_string()), ctx.tracestate);
+        assert_eq!(
+            Some([0xb7, 0xad, 0x6b, 0x71, 0x69, 0x20, 0x33, 0x31]),
+            span.data.parent_span_id
+        );
+        assert!(span
+            .traceparent()
+            .starts_with("00-0af7651916cd43dd8448eb211c80319c-"));
+        assert!(span.traceparent().ends_with("-01"));
+
+        for invalid in [
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            let req = TestRequest::get()
+                .header("traceparent", invalid)
+                .to_http_request();
+            let span = tracer.start_request(&req);
+            assert_eq!(None, span.data.parent_span_id);
+            // new traces follow sample ratio
+            assert!(!TraceContext::get(&req).unwrap().sampled);
+            assert!(span.traceparent().ends_with("-00"));
+        }
+    }
+}
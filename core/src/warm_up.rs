@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use actix_web::client::Client;
+use actix_web::http::header::HOST;
+use actix_web::http::Uri;
+use futures::stream::{self, StreamExt};
+use log::{info, warn};
+
+const WARM_UP_TIMEOUT: Duration = Duration::from_secs(30);
+const CONCURRENT_REQUESTS: usize = 4;
+
+/// Requests urls through the proxy listener so responses get cached,
+/// host of absolute urls is sent as Host header.
+pub async fn warm_up(listener: String, urls: Vec<String>) {
+    if urls.is_empty() {
+        return;
+    }
+    let client = Client::builder().timeout(WARM_UP_TIMEOUT).finish();
+    let total = urls.len();
+    let warmed = stream::iter(urls)
+        .map(|url| {
+            let client = &client;
+            let listener = &listener;
+            async move {
+                let (path, host) = match request_target(&url) {
+                    Some(target) => target,
+                    None => {
+                        warn!("Cache warm up of {} skipped, invalid url", &url);
+                        return false;
+                    }
+                };
+                let mut req = client.get(format!("http://{}{}", listener, path));
+                if let Some(host) = host {
+                    req = req.header(HOST, host);
+                }
+                match req.send().await {
+                    Ok(mut res) => {
+                        // response has to be consumed to be cached
+                        let body = res.body().await;
+                        let ok = res.status().is_success() && body.is_ok();
+                        if !ok {
+                            warn!("Cache warm up of {} failed with {}", &url, res.status());
+                        }
+                        ok
+                    }
+                    Err(e) => {
+                        warn!("Cache warm up of {} failed. Err = {}", &url, e);
+                        false
+                    }
+                }
+            }
+        })
+        .buffer_unordered(CONCURRENT_REQUESTS)
+        .filter(|ok| futures::future::ready(*ok))
+        .count()
+        .await;
+    info!("Cache warmed up with {} of {} urls", warmed, total);
+}
+
+// path and query requested from listener along with the host of absolute url
+fn request_target(url: &str) -> Option<(String, Option<String>)> {
+    let uri = url.parse::<Uri>().ok()?;
+    let path = uri
+        .path_and_query()
+        .map(|p| p.as_str())
+        .filter(|p| p.starts_with('/'))
+        .unwrap_or("/")
+        .to_string();
+    match uri.authority() {
+        Some(authority) => Some((path, Some(authority.to_string()))),
+        None if url.starts_with('/') => Some((path, None)),
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use actix_web::{test, web, App, HttpRequest, HttpResponse};
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn should_request_urls_through_listener() {
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let listener = {
+            let requested = requested.clone();
+            test::start(move || {
+                let requested = requested.clone();
+                App::new().default_service(web::to(move |req: HttpRequest| {
+                    let host = req
+                        .headers()
+                        .get(HOST)
+                        .and_then(|h| h.to_str().ok())
+                        .unwrap_or_default()
+                        .to_string();
+                    requested
+                        .lock()
+                        .expect("requests lock poisoned!")
+                        .push((req.uri().to_string(), host));
+                    HttpResponse::Ok().finish()
+                }))
+            })
+        };
+        let addr = listener.addr().to_string();
+
+        warm_up(
+            addr.clone(),
+            vec![
+                "/abc/index.html".to_string(),
+                "http://example.com:8080/abc/app.js?v=1".to_string(),
+                "not a url".to_string(),
+            ],
+        )
+        .await;
+
+        let mut requested = requested.lock().expect("requests lock poisoned!").clone();
+        requested.sort();
+        assert_eq!(
+            vec![
+                (
+                    "/abc/app.js?v=1".to_string(),
+                    "example.com:8080".to_string()
+                ),
+                ("/abc/index.html".to_string(), addr),
+            ],
+            requested
+        );
+    }
+}
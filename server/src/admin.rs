@@ -0,0 +1,232 @@
+use actix_web::{web, HttpResponse};
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use cache::{CacheEntryInfo, CacheStorage};
+use core::Metrics;
+
+use crate::ErrWrapper;
+
+#[derive(Debug, Deserialize)]
+pub struct PurgeQuery {
+    key: Option<String>,
+    prefix: Option<String>,
+    regex: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EntryQuery {
+    key: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry {
+    key: String,
+    status: u16,
+    size: usize,
+    ttl_secs: u64,
+    hits: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Purged {
+    purged: usize,
+}
+
+impl From<CacheEntryInfo> for Entry {
+    fn from(info: CacheEntryInfo) -> Self {
+        Entry {
+            key: info.key.to_string(),
+            status: info.status_code.as_u16(),
+            size: info.size,
+            ttl_secs: info.ttl.as_secs(),
+            hits: info.hits,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/cache")
+            .route(web::get().to(list_entries))
+            .route(web::delete().to(purge)),
+    )
+    .service(web::resource("/cache/entry").route(web::get().to(inspect_entry)))
+    .service(web::resource("/cache/stats").route(web::get().to(stats)))
+    .service(web::resource("/metrics").route(web::get().to(metrics)));
+}
+
+async fn list_entries(cache: web::Data<dyn CacheStorage>) -> HttpResponse {
+    let entries = cache
+        .entries()
+        .into_iter()
+        .map(Entry::from)
+        .collect::<Vec<Entry>>();
+    HttpResponse::Ok().json(entries)
+}
+
+async fn inspect_entry(
+    query: web::Query<EntryQuery>,
+    cache: web::Data<dyn CacheStorage>,
+) -> HttpResponse {
+    match cache.entry(&query.key) {
+        Some(info) => HttpResponse::Ok().json(Entry::from(info)),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+async fn stats(cache: web::Data<dyn CacheStorage>) -> HttpResponse {
+    HttpResponse::Ok().json(cache.stats())
+}
+
+async fn metrics(
+    metrics: web::Data<Metrics>,
+    cache: web::Data<dyn CacheStorage>,
+) -> crate::Response<HttpResponse> {
+    let metrics = metrics.render(&**cache)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics))
+}
+
+async fn purge(query: web::Query<PurgeQuery>, cache: web::Data<dyn CacheStorage>) -> HttpResponse {
+    match purge_matching(&query, &**cache) {
+        Ok(purged) => HttpResponse::Ok().json(Purged { purged }),
+        // only an invalid regex fails, it's the client's error
+        Err(e) => HttpResponse::BadRequest().json(ErrWrapper::from(e)),
+    }
+}
+
+fn purge_matching(query: &PurgeQuery, cache: &dyn CacheStorage) -> Result<usize> {
+    let purged = if let Some(key) = &query.key {
+        // variants of the response are stored under keys extended with `|`
+        let variants = format!("{}|", key);
+        cache.remove(key) as usize + cache.purge(&|k| k.starts_with(variants.as_str()))
+    } else if let Some(prefix) = &query.prefix {
+        cache.purge(&|k| k.starts_with(prefix.as_str()))
+    } else if let Some(regex) = &query.regex {
+        let regex = Regex::new(regex)?;
+        cache.purge(&|k| regex.is_match(k))
+    } else {
+        cache.purge(&|_| true)
+    };
+    Ok(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::http::{HeaderMap, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::web::Bytes;
+    use actix_web::{web, App};
+    use serde_json::Value;
+
+    use cache::{CacheStorage, CachedResponse, ResponseCache};
+
+    use crate::admin::configure;
+
+    fn cache_with(keys: &[&str]) -> Arc<ResponseCache> {
+        let cache = Arc::new(ResponseCache::with_capacity(10));
+        let expire_at = SystemTime::now() + Duration::from_secs(60);
+        for key in keys {
+            let response = CachedResponse {
+                status_code: StatusCode::OK,
+                headers: HeaderMap::new(),
+                body: Bytes::from_static(b"body").into(),
+                ttl: expire_at,
+            };
+            cache.put(Arc::from(*key), response, expire_at);
+        }
+        cache
+    }
+
+    async fn call(cache: Arc<ResponseCache>, req: TestRequest) -> (StatusCode, Value) {
+        let cache: Arc<dyn CacheStorage> = cache;
+        let mut app = test::init_service(
+            App::new()
+                .app_data(web::Data::from(cache))
+                .configure(configure),
+        )
+        .await;
+        let res = test::call_service(&mut app, req.to_request()).await;
+        let status = res.status();
+        let body = test::read_body(res).await;
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    fn keys(cache: &ResponseCache) -> Vec<String> {
+        let mut keys = cache
+            .entries()
+            .into_iter()
+            .map(|info| info.key.to_string())
+            .collect::<Vec<String>>();
+        keys.sort();
+        keys
+    }
+
+    #[actix_rt::test]
+    async fn should_list_and_inspect_entries() {
+        let cache = cache_with(&["/a", "/b"]);
+
+        let (status, body) = call(cache.clone(), TestRequest::get().uri("/cache")).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(2, body.as_array().unwrap().len());
+
+        let req = TestRequest::get().uri("/cache/entry?key=/a");
+        let (status, body) = call(cache.clone(), req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("/a", body["key"]);
+        assert_eq!(4, body["size"]);
+
+        let req = TestRequest::get().uri("/cache/entry?key=/c");
+        let (status, _) = call(cache, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+    }
+
+    #[actix_rt::test]
+    async fn should_purge_key_with_its_variants() {
+        let cache = cache_with(&["/a", "/a|accept=gzip", "/a|accept=br", "/ab"]);
+
+        let req = TestRequest::delete().uri("/cache?key=/a");
+        let (status, body) = call(cache.clone(), req).await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(3, body["purged"]);
+        assert_eq!(vec!["/ab"], keys(&cache));
+    }
+
+    #[actix_rt::test]
+    async fn should_purge_by_prefix_regex_or_everything() {
+        let cache = cache_with(&["/a/1", "/a/2", "/b/1.css", "/c"]);
+
+        let req = TestRequest::delete().uri("/cache?prefix=/a/");
+        let (_, body) = call(cache.clone(), req).await;
+        assert_eq!(2, body["purged"]);
+
+        let req = TestRequest::delete().uri("/cache?regex=.*%5C.css%24");
+        let (_, body) = call(cache.clone(), req).await;
+        assert_eq!(1, body["purged"]);
+        assert_eq!(vec!["/c"], keys(&cache));
+
+        let (_, body) = call(cache.clone(), TestRequest::delete().uri("/cache")).await;
+        assert_eq!(1, body["purged"]);
+        assert!(keys(&cache).is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn should_reject_invalid_regex() {
+        let cache = cache_with(&["/a"]);
+
+        let req = TestRequest::delete().uri("/cache?regex=%5B");
+        let (status, body) = call(cache.clone(), req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, status);
+        assert!(body["msg"]
+            .as_str()
+            .unwrap()
+            .contains("unclosed character class"));
+        assert_eq!(vec!["/a"], keys(&cache));
+    }
+}
@@ -1,24 +1,51 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
-
+use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol};
+use actix_server::Server;
+use actix_service::{fn_service, map_config, pipeline_factory};
+use actix_web::dev::AppConfig;
 use actix_web::middleware::Logger;
+use actix_web::rt::net::TcpStream;
+use actix_web::rt::signal::unix::{signal, SignalKind};
+use actix_web::rt::time::timeout;
 use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, ResponseError};
 use anyhow::anyhow;
+use log::{error, info};
 
 use serde::Serialize;
 
 use structopt::StructOpt;
 
 use core::init_logger;
+use core::read_trusted_proxy_header;
+use core::reopen_log;
+use core::warm_up;
+use core::AccessLogger;
 use core::Balancer;
 use core::Configuration;
 use core::FileWatcher;
+use core::Metrics;
 use core::Proxy;
+use core::RequestId;
+use core::Tracer;
+use core::TrustedProxies;
+
+mod admin;
 
 type Response<T> = Result<T, ErrWrapper>;
 
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// connection settings of both listeners, actix defaults
+const KEEP_ALIVE_SECS: usize = 5;
+const CLIENT_TIMEOUT_MS: u64 = 5000;
+const CLIENT_SHUTDOWN_MS: u64 = 5000;
+
 #[derive(StructOpt, Debug)]
 pub struct CliCfg {
     #[structopt(
@@ -59,7 +86,46 @@ async fn proxy_request(
     body: web::Bytes,
     proxy: web::Data<Proxy>,
 ) -> Response<HttpResponse> {
-    Ok(proxy.proxy(req, body).await?)
+    Ok(proxy.into_inner().proxy(req, body).await?)
+}
+
+// logrotate moves log files and signals roxy to continue in new ones
+async fn reopen_logs_on_signal(access_logger: AccessLogger) {
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            error!(
+                "Can't listen for SIGUSR1, logs won't be reopened. Err = {}",
+                e
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        info!("Reopening log files");
+        if let Err(e) = reopen_log() {
+            error!("Error reopening log file. Err = {}", e);
+        }
+        if let Err(e) = access_logger.reopen() {
+            error!("Error reopening access log. Err = {}", e);
+        }
+    }
+}
+
+// client address carried by PROXY protocol header of trusted proxy becomes the connection peer address
+async fn accept_proxy_protocol(
+    mut stream: TcpStream,
+    trusted_proxies: TrustedProxies,
+) -> Result<(TcpStream, Protocol, Option<SocketAddr>), DispatchError> {
+    let _ = stream.set_nodelay(true);
+    let peer = stream.peer_addr().ok();
+    let client = timeout(
+        PROXY_HEADER_TIMEOUT,
+        read_trusted_proxy_header(&mut stream, peer, &trusted_proxies),
+    )
+    .await
+    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "PROXY header timed out"))??;
+    Ok((stream, Protocol::Http1, client))
 }
 
 #[actix_web::main]
@@ -68,26 +134,120 @@ async fn main() -> anyhow::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
 
     let cli_cfg = CliCfg::from_args();
-    let configuration = Arc::new(Configuration::new(&cli_cfg.proxy_config_path)?);
+    let metrics = Arc::new(Metrics::new());
+    let configuration =
+        Arc::new(Configuration::new(&cli_cfg.proxy_config_path)?.with_metrics(metrics.clone()));
     let service_config = configuration.service_config();
-    init_logger(service_config.log_path, service_config.dev_mode);
+    init_logger(
+        service_config.log_path,
+        service_config.dev_mode,
+        &configuration.logging_config(),
+    )?;
 
     let watcher = FileWatcher::new(&cli_cfg.proxy_config_path);
     watcher.register_listener(Box::new(configuration.clone()));
     watcher.watch_file_changes()?;
 
-    let proxy = Proxy::new(Balancer::new(configuration.clone()))?;
+    let proxy = Proxy::new(
+        Balancer::new(configuration.clone()),
+        configuration.cache_store(),
+    )?
+    .with_response_compression(configuration.compression_config())
+    .with_forwarding(configuration.forwarding_config())
+    .with_metrics(metrics.clone());
+    let trusted_proxies = TrustedProxies::new(&configuration.trusted_proxies())?;
+    let tracer = configuration
+        .tracing_config()
+        .map(|tracing| Arc::new(Tracer::new(tracing)));
+    if let Some(tracer) = &tracer {
+        actix_web::rt::spawn(tracer.clone().export_periodically());
+    }
+    let proxy = proxy
+        .with_trusted_proxies(trusted_proxies.clone())
+        .with_tracer(tracer.clone());
+    if let Some(admin) = configuration.admin_config() {
+        let cache = web::Data::from(proxy.cache());
+        let metrics = web::Data::from(metrics);
+        let admin_server = HttpServer::new(move || {
+            App::new()
+                .wrap(Logger::default())
+                .app_data(cache.clone())
+                .app_data(metrics.clone())
+                .configure(admin::configure)
+        })
+        .bind(format!("{}:{}", admin.ip, admin.port))?
+        .workers(1)
+        .run();
+        actix_web::rt::spawn(async {
+            if let Err(e) = admin_server.await {
+                error!("Admin server failed {}", e);
+            }
+        });
+    }
+
+    let warm_up_urls = configuration.warm_up_urls();
     let data = web::Data::new(proxy);
-    HttpServer::new(move || {
+    let proxy = data.clone();
+    let request_id = RequestId::new(&service_config.request_id_header)?;
+    let header_proxies = trusted_proxies.clone();
+    let access_logger = AccessLogger::new(
+        configuration.access_log_config(),
+        trusted_proxies,
+        &service_config.request_id_header,
+    )?;
+    actix_web::rt::spawn(reopen_logs_on_signal(access_logger.clone()));
+    let app = move || {
         App::new()
-            .wrap(Logger::default())
+            .wrap(access_logger.clone())
+            .wrap(request_id.clone())
             .app_data(data.clone())
             .service(web::resource("/*").to(proxy_request))
-    })
-    .bind(format!("{}:{}", service_config.ip, service_config.port))?
-    .workers(service_config.workers)
-    .shutdown_timeout(10)
-    .run()
-    .await
-    .map_err(|e| anyhow!("Startup failed {}", e))
+    };
+    let addr = format!("{}:{}", service_config.ip, service_config.port);
+    let server = if service_config.proxy_protocol {
+        let listener = std::net::TcpListener::bind(&addr)?;
+        let local_addr = listener.local_addr()?;
+        Server::build()
+            .listen("roxy-proxy-protocol", listener, move || {
+                let trusted_proxies = header_proxies.clone();
+                pipeline_factory(fn_service(move |stream| {
+                    accept_proxy_protocol(stream, trusted_proxies.clone())
+                }))
+                .and_then(
+                    HttpService::build()
+                        .keep_alive(KEEP_ALIVE_SECS)
+                        .client_timeout(CLIENT_TIMEOUT_MS)
+                        .client_disconnect(CLIENT_SHUTDOWN_MS)
+                        .local_addr(local_addr)
+                        // actix-web 3 builds AppConfig with the bound address only in HttpServer,
+                        // the listener is plain http and roxy reads host from request headers
+                        .finish(map_config(app(), |_| AppConfig::default())),
+                )
+            })?
+            .workers(service_config.workers)
+            .shutdown_timeout(10)
+            .run()
+    } else {
+        HttpServer::new(app)
+            .keep_alive(KEEP_ALIVE_SECS)
+            .client_timeout(CLIENT_TIMEOUT_MS)
+            .client_shutdown(CLIENT_SHUTDOWN_MS)
+            .bind(&addr)?
+            .workers(service_config.workers)
+            .shutdown_timeout(10)
+            .run()
+    };
+    actix_web::rt::spawn(warm_up(
+        format!("{}:{}", service_config.ip, service_config.port),
+        warm_up_urls,
+    ));
+    let result = server.await.map_err(|e| anyhow!("Startup failed {}", e));
+
+    proxy.shutdown();
+    if let Some(tracer) = tracer {
+        if let Err(e) = tracer.export().await {
+            error!("{}", e);
+        }
+    }
+    result
 }